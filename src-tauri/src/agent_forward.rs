@@ -0,0 +1,112 @@
+use crate::models::{chrono_timestamp, AgentForwardUse, ServerConfig};
+use crate::transport::{self, ConnectPrompt, Prompter};
+use anyhow::{bail, Result};
+use russh::{client, Channel};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+
+// Agent messages are small; anything bigger is not a well-behaved client
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// Who is using the forwarded agent, for the audit notices
+#[derive(Clone)]
+pub(crate) struct AgentOrigin {
+    server_id: String,
+    server_name: String,
+    host: String,
+}
+
+impl AgentOrigin {
+    pub fn new(server: &ServerConfig) -> Self {
+        Self {
+            server_id: server.id.clone(),
+            server_name: server.name.clone(),
+            host: server.host.clone(),
+        }
+    }
+}
+
+/// Serves an `auth-agent@openssh.com` channel opened by the server from the
+/// local ssh-agent, reporting every signature it asks for
+pub(crate) fn accept(
+    channel: Channel<client::Msg>,
+    origin: AgentOrigin,
+    prompter: Option<Prompter>,
+) {
+    tokio::spawn(async move {
+        let agent = match transport::connect_agent_socket().await {
+            Ok(agent) => agent,
+            Err(_) => {
+                channel.close().await.ok();
+                return;
+            }
+        };
+
+        let (mut agent_read, mut agent_write) = tokio::io::split(agent);
+        let (mut remote_read, mut remote_write) = tokio::io::split(channel.into_stream());
+        let requests = relay_requests(
+            &mut remote_read,
+            &mut agent_write,
+            &origin,
+            prompter.as_ref(),
+        );
+        let replies = async {
+            tokio::io::copy(&mut agent_read, &mut remote_write).await?;
+            remote_write.shutdown().await?;
+            Ok(())
+        };
+        tokio::try_join!(requests, replies).ok();
+    });
+}
+
+/// Passes agent requests from the server through one message at a time, so
+/// sign requests can be seen before the agent answers them
+async fn relay_requests<R, W>(
+    remote: &mut R,
+    agent: &mut W,
+    origin: &AgentOrigin,
+    prompter: Option<&Prompter>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let mut len = [0u8; 4];
+        if remote.read_exact(&mut len).await.is_err() {
+            // The server closed the channel
+            return Ok(agent.shutdown().await?);
+        }
+        let message_len = u32::from_be_bytes(len) as usize;
+        if message_len > MAX_MESSAGE_LEN {
+            bail!("Agent request of {} bytes is too large", message_len);
+        }
+        let mut message = vec![0u8; message_len];
+        remote.read_exact(&mut message).await?;
+
+        if message.first() == Some(&SSH_AGENTC_SIGN_REQUEST) {
+            if let Some(prompter) = prompter {
+                let notice = AgentForwardUse {
+                    server_id: origin.server_id.clone(),
+                    server_name: origin.server_name.clone(),
+                    host: origin.host.clone(),
+                    fingerprint: sign_request_fingerprint(&message[1..]),
+                    used_at: chrono_timestamp(),
+                };
+                prompter.send(ConnectPrompt::AgentUsed(notice)).ok();
+            }
+        }
+
+        agent.write_all(&len).await?;
+        agent.write_all(&message).await?;
+    }
+}
+
+/// Fingerprint of the key blob a sign request starts with
+fn sign_request_fingerprint(body: &[u8]) -> Option<String> {
+    let len = u32::from_be_bytes(body.get(..4)?.try_into().ok()?) as usize;
+    let blob = body.get(4..4 + len)?;
+    let key = ssh_key::PublicKey::from_bytes(blob).ok()?;
+    Some(key.fingerprint(ssh_key::HashAlg::Sha256).to_string())
+}
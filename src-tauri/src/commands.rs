@@ -1,11 +1,23 @@
-use crate::models::{AuthMethod, FileEntry, ProxyConfig, ProxyType, ServerConfig, TerminalSize};
+use crate::exec::{self, ExecOptions};
+use crate::fleet;
+use crate::forward;
+use crate::models::{
+    AuthMethod, BroadcastFailure, BroadcastMember, CertificateInfo, DynamicForward, ExecOutput,
+    ExecResult, FileEntry, FleetHostResult, FleetProgress, FleetTarget, ForwardConnection,
+    ForwardStatus, HostKeyDecision, KnownHost, KnownHostsImport, LocalForward, ProxyConfig,
+    ProxyType, QueuedTransfer, RemoteForward, ResumeMode, ServerConfig, TerminalSize,
+    TransferProgress, TransferQueueState, TransferRequest, TransferSummary,
+};
+use crate::pool;
+use crate::queue;
 use crate::sftp::{self, SftpConnection};
 use crate::ssh::{self, SshSession};
 use crate::storage;
+use crate::transport;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::Path;
 use tauri::{AppHandle, Emitter};
-use tokio::fs;
+use tokio::sync::mpsc;
 
 // ============ Server Management Commands ============
 
@@ -17,9 +29,20 @@ pub struct ServerInfo {
     pub port: u16,
     pub username: String,
     pub auth_type: String,
+    pub certificate: Option<CertificateInfo>,
     pub has_proxy: bool,
     pub has_jump_host: bool,
-    pub jump_host: Option<String>,
+    pub jump_hosts: Vec<String>,
+    pub local_forwards: Vec<LocalForward>,
+    pub remote_forwards: Vec<RemoteForward>,
+    pub dynamic_forwards: Vec<DynamicForward>,
+    pub forward_agent: bool,
+    pub forward_x11: bool,
+    pub keepalive_interval: u32,
+    pub keepalive_max: u32,
+    pub auto_reconnect: bool,
+    pub tags: Vec<String>,
+    pub max_transfers: u32,
     pub notes: Option<String>,
 }
 
@@ -34,10 +57,37 @@ impl From<&ServerConfig> for ServerInfo {
             auth_type: match &config.auth {
                 AuthMethod::Password(_) => "password".to_string(),
                 AuthMethod::PrivateKey { .. } => "key".to_string(),
+                AuthMethod::Agent { .. } => "agent".to_string(),
+                AuthMethod::KeyboardInteractive => "interactive".to_string(),
+            },
+            certificate: match &config.auth {
+                AuthMethod::PrivateKey {
+                    certificate: Some(certificate),
+                    ..
+                } => transport::certificate_info(certificate).ok(),
+                _ => None,
             },
             has_proxy: config.proxy.is_some(),
-            has_jump_host: config.jump_host.is_some(),
-            jump_host: config.jump_host.clone(),
+            has_jump_host: !config.jump_hosts.is_empty(),
+            jump_hosts: config.jump_hosts.clone(),
+            local_forwards: config.local_forwards.clone(),
+            remote_forwards: config.remote_forwards.clone(),
+            // The list never needs the SOCKS password
+            dynamic_forwards: config
+                .dynamic_forwards
+                .iter()
+                .map(|rule| DynamicForward {
+                    password: None,
+                    ..rule.clone()
+                })
+                .collect(),
+            forward_agent: config.forward_agent,
+            forward_x11: config.forward_x11,
+            keepalive_interval: config.keepalive_interval,
+            keepalive_max: config.keepalive_max,
+            auto_reconnect: config.auto_reconnect,
+            tags: config.tags.clone(),
+            max_transfers: config.max_transfers,
             notes: config.notes.clone(),
         }
     }
@@ -67,25 +117,52 @@ pub struct SaveServerRequest {
     pub password: Option<String>,
     pub private_key: Option<String>,
     pub passphrase: Option<String>,
+    pub certificate: Option<String>,
+    pub agent_fingerprint: Option<String>,
     pub proxy_enabled: bool,
     pub proxy_type: Option<String>,
     pub proxy_host: Option<String>,
     pub proxy_port: Option<u16>,
     pub proxy_username: Option<String>,
     pub proxy_password: Option<String>,
-    pub jump_host: Option<String>,
+    #[serde(default)]
+    pub jump_hosts: Vec<String>,
+    #[serde(default)]
+    pub local_forwards: Vec<LocalForward>,
+    #[serde(default)]
+    pub remote_forwards: Vec<RemoteForward>,
+    #[serde(default)]
+    pub dynamic_forwards: Vec<DynamicForward>,
+    #[serde(default)]
+    pub forward_agent: bool,
+    #[serde(default)]
+    pub forward_x11: bool,
+    #[serde(default = "crate::models::default_keepalive_interval")]
+    pub keepalive_interval: u32,
+    #[serde(default = "crate::models::default_keepalive_max")]
+    pub keepalive_max: u32,
+    #[serde(default)]
+    pub auto_reconnect: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "crate::models::default_max_transfers")]
+    pub max_transfers: u32,
     pub notes: Option<String>,
 }
 
 #[tauri::command]
 pub fn save_server(request: SaveServerRequest) -> Result<ServerInfo, String> {
-    let auth = if request.auth_type == "key" {
-        AuthMethod::PrivateKey {
+    let auth = match request.auth_type.as_str() {
+        "key" => AuthMethod::PrivateKey {
             key: request.private_key.ok_or("Private key is required")?,
             passphrase: request.passphrase.filter(|p| !p.is_empty()),
-        }
-    } else {
-        AuthMethod::Password(request.password.ok_or("Password is required")?)
+            certificate: request.certificate.filter(|c| !c.trim().is_empty()),
+        },
+        "agent" => AuthMethod::Agent {
+            fingerprint: request.agent_fingerprint.filter(|f| !f.trim().is_empty()),
+        },
+        "interactive" => AuthMethod::KeyboardInteractive,
+        _ => AuthMethod::Password(request.password.ok_or("Password is required")?),
     };
 
     let proxy = if request.proxy_enabled {
@@ -103,8 +180,61 @@ pub fn save_server(request: SaveServerRequest) -> Result<ServerInfo, String> {
         None
     };
 
-    // Filter empty jump_host
-    let jump_host = request.jump_host.filter(|h| !h.is_empty());
+    // Filter empty jump hosts
+    let jump_hosts: Vec<String> = request
+        .jump_hosts
+        .into_iter()
+        .filter(|h| !h.is_empty())
+        .collect();
+
+    let mut tags: Vec<String> = Vec::new();
+    for tag in request.tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+
+    let mut local_forwards = request.local_forwards;
+    for rule in &mut local_forwards {
+        if rule.local_port == 0 || rule.remote_port == 0 || rule.remote_host.trim().is_empty() {
+            return Err("Port forward needs a local port, remote host and remote port".to_string());
+        }
+        if rule.bind_address.trim().is_empty() {
+            rule.bind_address = "127.0.0.1".to_string();
+        }
+        if rule.id.is_empty() {
+            rule.id = uuid::Uuid::new_v4().to_string();
+        }
+    }
+
+    let mut remote_forwards = request.remote_forwards;
+    for rule in &mut remote_forwards {
+        if rule.local_port == 0 || rule.local_host.trim().is_empty() {
+            return Err("Remote forward needs a local host and local port".to_string());
+        }
+        if rule.bind_address.trim().is_empty() {
+            rule.bind_address = "localhost".to_string();
+        }
+        if rule.id.is_empty() {
+            rule.id = uuid::Uuid::new_v4().to_string();
+        }
+    }
+
+    let mut dynamic_forwards = request.dynamic_forwards;
+    for rule in &mut dynamic_forwards {
+        if rule.local_port == 0 {
+            return Err("Dynamic forward needs a local port".to_string());
+        }
+        if rule.bind_address.trim().is_empty() {
+            rule.bind_address = "127.0.0.1".to_string();
+        }
+        rule.username = rule.username.take().filter(|u| !u.is_empty());
+        rule.password = rule.password.take().filter(|_| rule.username.is_some());
+        if rule.id.is_empty() {
+            rule.id = uuid::Uuid::new_v4().to_string();
+        }
+    }
 
     let server = if let Some(id) = request.id {
         let mut existing = storage::get_server(&id).ok_or("Server not found")?;
@@ -114,23 +244,49 @@ pub fn save_server(request: SaveServerRequest) -> Result<ServerInfo, String> {
         existing.username = request.username;
         existing.auth = auth;
         existing.proxy = proxy;
-        existing.jump_host = jump_host;
+        existing.jump_hosts = jump_hosts;
+        existing.local_forwards = local_forwards;
+        existing.remote_forwards = remote_forwards;
+        existing.dynamic_forwards = dynamic_forwards;
+        existing.forward_agent = request.forward_agent;
+        existing.forward_x11 = request.forward_x11;
+        existing.keepalive_interval = request.keepalive_interval;
+        existing.keepalive_max = request.keepalive_max;
+        existing.auto_reconnect = request.auto_reconnect;
+        existing.tags = tags;
+        existing.max_transfers = request.max_transfers.max(1);
         existing.notes = request.notes;
         existing
     } else {
         let mut server = ServerConfig::new(request.name, request.host, request.port, request.username, auth);
         server.proxy = proxy;
-        server.jump_host = jump_host;
+        server.jump_hosts = jump_hosts;
+        server.local_forwards = local_forwards;
+        server.remote_forwards = remote_forwards;
+        server.dynamic_forwards = dynamic_forwards;
+        server.forward_agent = request.forward_agent;
+        server.forward_x11 = request.forward_x11;
+        server.keepalive_interval = request.keepalive_interval;
+        server.keepalive_max = request.keepalive_max;
+        server.auto_reconnect = request.auto_reconnect;
+        server.tags = tags;
+        server.max_transfers = request.max_transfers.max(1);
         server.notes = request.notes;
         server
     };
 
+    // Reject chains that loop back or reference missing servers
+    transport::resolve_jump_chain(&server).map_err(|e| e.to_string())?;
+
     let saved = storage::save_server(server).map_err(|e| e.to_string())?;
+    // New sessions should pick up the changed settings
+    pool::evict(&saved.id);
     Ok(ServerInfo::from(&saved))
 }
 
 #[tauri::command]
 pub fn delete_server(id: String) -> Result<(), String> {
+    pool::evict(&id);
     storage::delete_server(&id).map_err(|e| e.to_string())
 }
 
@@ -144,8 +300,84 @@ pub fn import_servers(encrypted_data: String, password: String) -> Result<usize,
     storage::import_servers(&encrypted_data, &password).map_err(|e| e.to_string())
 }
 
+// ============ Known Hosts Commands ============
+
+#[tauri::command]
+pub fn get_known_hosts() -> Vec<KnownHost> {
+    storage::get_known_hosts()
+}
+
+/// Trusts a key given by the user. Keys presented while connecting are
+/// accepted through `respond_host_key` instead.
+#[tauri::command]
+pub fn accept_host_key(
+    host: String,
+    port: u16,
+    key_type: String,
+    public_key: String,
+) -> Result<KnownHost, String> {
+    storage::accept_host_key(&host, port, &key_type, &public_key).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn revoke_host_key(host: String, port: u16, key_type: String) -> Result<(), String> {
+    storage::revoke_host_key(&host, port, &key_type).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn forget_host_key(host: String, port: u16, key_type: String) -> Result<(), String> {
+    storage::forget_host_key(&host, port, &key_type).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn import_known_hosts(content: String) -> Result<KnownHostsImport, String> {
+    storage::import_known_hosts(&content).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn export_known_hosts(hash_hosts: bool) -> String {
+    storage::export_known_hosts(hash_hosts)
+}
+
+#[tauri::command]
+pub async fn respond_host_key(prompt_id: String, decision: HostKeyDecision) -> Result<(), String> {
+    transport::answer_host_key_prompt(&prompt_id, decision)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 // ============ SSH Commands ============
 
+#[tauri::command]
+pub async fn respond_auth_prompt(
+    prompt_id: String,
+    answers: Option<Vec<String>>,
+) -> Result<(), String> {
+    transport::answer_auth_prompt(&prompt_id, answers)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Forwards host key and login prompts, and agent use notices, to the frontend
+fn connect_prompter(app: &AppHandle) -> transport::Prompter {
+    let (tx, mut rx) = mpsc::unbounded_channel::<transport::ConnectPrompt>();
+    let app_handle = app.clone();
+    tokio::spawn(async move {
+        while let Some(prompt) = rx.recv().await {
+            let _ = match prompt {
+                transport::ConnectPrompt::HostKey(prompt) => {
+                    app_handle.emit("host-key-prompt", prompt)
+                }
+                transport::ConnectPrompt::Auth(prompt) => app_handle.emit("auth-prompt", prompt),
+                transport::ConnectPrompt::AgentUsed(notice) => {
+                    app_handle.emit("agent-forward-used", notice)
+                }
+            };
+        }
+    });
+    tx
+}
+
 #[tauri::command]
 pub async fn ssh_connect(
     app: AppHandle,
@@ -155,46 +387,129 @@ pub async fn ssh_connect(
 ) -> Result<String, String> {
     let server = storage::get_server(&server_id).ok_or("Server not found")?;
 
-    let session = SshSession::connect(&server)
+    let session = SshSession::connect(&server, Some(connect_prompter(&app)))
         .await
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| format!("{:#}", e))?;
 
     let session_id = session.session_id().to_string();
 
-    session
-        .open_shell(TerminalSize { cols, rows })
+    // The first shell's channel shares the session's ID
+    let streams = match session
+        .open_shell(session_id.clone(), TerminalSize { cols, rows })
+        .await
+    {
+        Ok(streams) => streams,
+        Err(e) => {
+            // Don't keep a session around that never got a shell
+            ssh::remove_session(&session_id).await;
+            return Err(e.to_string());
+        }
+    };
+    relay_shell(&app, &session_id, streams);
+
+    Ok(session_id)
+}
+
+/// Opens another shell on an existing session's connection, for a new tab or
+/// split. Returns its channel ID, which names its events like a session ID.
+#[tauri::command]
+pub async fn ssh_open_channel(
+    app: AppHandle,
+    session_id: String,
+    cols: u32,
+    rows: u32,
+) -> Result<String, String> {
+    let session = ssh::get_session(&session_id)
+        .await
+        .ok_or("Session not found")?;
+    let channel_id = uuid::Uuid::new_v4().to_string();
+
+    let streams = session
+        .open_shell(channel_id.clone(), TerminalSize { cols, rows })
         .await
         .map_err(|e| e.to_string())?;
+    relay_shell(&app, &channel_id, streams);
 
-    // Spawn output handler
-    if let Some(mut rx) = session.take_output_receiver().await {
-        let app_handle = app.clone();
-        let sid = session_id.clone();
-        tokio::spawn(async move {
-            while let Some(data) = rx.recv().await {
-                let _ = app_handle.emit(&format!("ssh-data-{}", sid), data);
-            }
-        });
-    }
+    Ok(channel_id)
+}
 
-    Ok(session_id)
+/// Emits a shell's output as `ssh-data-{id}`, its connection state changes as
+/// `ssh-state-{id}` and its exit as `ssh-exit-{id}`
+fn relay_shell(app: &AppHandle, channel_id: &str, streams: ssh::ShellStreams) {
+    let ssh::ShellStreams {
+        mut output,
+        mut events,
+    } = streams;
+
+    let app_handle = app.clone();
+    let id = channel_id.to_string();
+    tokio::spawn(async move {
+        while let Some(data) = output.recv().await {
+            let _ = app_handle.emit(&format!("ssh-data-{}", id), data);
+        }
+    });
+
+    let app_handle = app.clone();
+    let id = channel_id.to_string();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let _ = match event {
+                ssh::SessionEvent::State(change) => {
+                    app_handle.emit(&format!("ssh-state-{}", id), change)
+                }
+                ssh::SessionEvent::Exit(exit) => app_handle.emit(&format!("ssh-exit-{}", id), exit),
+            };
+        }
+    });
 }
 
+/// `channel_id` picks one of the session's shells, the first one by default
 #[tauri::command]
-pub async fn ssh_write(session_id: String, data: Vec<u8>) -> Result<(), String> {
-    let session = ssh::get_session(&session_id).await.ok_or("Session not found")?;
-    session.write(&data).await.map_err(|e| e.to_string())
+pub async fn ssh_write(
+    session_id: String,
+    channel_id: Option<String>,
+    data: Vec<u8>,
+) -> Result<(), String> {
+    let session = ssh::get_session(&session_id)
+        .await
+        .ok_or("Session not found")?;
+    let channel_id = channel_id.unwrap_or(session_id);
+    session
+        .write(&channel_id, &data)
+        .await
+        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-pub async fn ssh_resize(session_id: String, cols: u32, rows: u32) -> Result<(), String> {
-    let session = ssh::get_session(&session_id).await.ok_or("Session not found")?;
+pub async fn ssh_resize(
+    session_id: String,
+    channel_id: Option<String>,
+    cols: u32,
+    rows: u32,
+) -> Result<(), String> {
+    let session = ssh::get_session(&session_id)
+        .await
+        .ok_or("Session not found")?;
+    let channel_id = channel_id.unwrap_or(session_id);
     session
-        .resize(TerminalSize { cols, rows })
+        .resize(&channel_id, TerminalSize { cols, rows })
         .await
         .map_err(|e| e.to_string())
 }
 
+/// Closes one shell. The session and its connection stay up while it has
+/// others open.
+#[tauri::command]
+pub async fn ssh_close_channel(session_id: String, channel_id: String) -> Result<(), String> {
+    if let Some(session) = ssh::get_session(&session_id).await {
+        session
+            .close_channel(&channel_id)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn ssh_disconnect(session_id: String) -> Result<(), String> {
     if let Some(session) = ssh::get_session(&session_id).await {
@@ -204,15 +519,151 @@ pub async fn ssh_disconnect(session_id: String) -> Result<(), String> {
     Ok(())
 }
 
+/// Groups terminals for `ssh_broadcast`, returning the group's ID
+#[tauri::command]
+pub async fn ssh_create_broadcast_group(members: Vec<BroadcastMember>) -> Result<String, String> {
+    Ok(ssh::create_broadcast_group(members).await)
+}
+
+/// Sends input to every terminal in a group, returning the ones it missed
+#[tauri::command]
+pub async fn ssh_broadcast(
+    group_id: String,
+    data: Vec<u8>,
+) -> Result<Vec<BroadcastFailure>, String> {
+    ssh::broadcast(&group_id, &data)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn ssh_remove_broadcast_group(group_id: String) -> Result<(), String> {
+    ssh::remove_broadcast_group(&group_id).await;
+    Ok(())
+}
+
+/// Runs a command on a saved server without opening a terminal
+#[tauri::command]
+pub async fn ssh_exec(
+    app: AppHandle,
+    server_id: String,
+    command: String,
+    pty: Option<bool>,
+    timeout_secs: Option<u64>,
+) -> Result<ExecResult, String> {
+    let server = storage::get_server(&server_id).ok_or("Server not found")?;
+    let options = exec_options(pty, timeout_secs);
+
+    exec::run(
+        &server,
+        Some(connect_prompter(&app)),
+        &command,
+        &options,
+        None,
+    )
+    .await
+    .map_err(|e| format!("{:#}", e))
+}
+
+/// Like `ssh_exec`, but emits the output as `ssh-exec-{exec_id}` while the
+/// command runs. The caller picks `exec_id` so it can listen before starting;
+/// the result it gets back has no output of its own.
+#[tauri::command]
+pub async fn ssh_exec_stream(
+    app: AppHandle,
+    server_id: String,
+    command: String,
+    exec_id: String,
+    pty: Option<bool>,
+    timeout_secs: Option<u64>,
+) -> Result<ExecResult, String> {
+    let server = storage::get_server(&server_id).ok_or("Server not found")?;
+    let options = exec_options(pty, timeout_secs);
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<ExecOutput>();
+    let app_handle = app.clone();
+    tokio::spawn(async move {
+        while let Some(output) = rx.recv().await {
+            let _ = app_handle.emit(&format!("ssh-exec-{}", exec_id), output);
+        }
+    });
+
+    exec::run(
+        &server,
+        Some(connect_prompter(&app)),
+        &command,
+        &options,
+        Some(tx),
+    )
+    .await
+    .map_err(|e| format!("{:#}", e))
+}
+
+/// Runs one command on many saved servers at once, up to `concurrency` at a
+/// time, emitting `fleet-progress-{run_id}` as each host starts and finishes
+#[tauri::command]
+pub async fn fleet_run(
+    app: AppHandle,
+    target: FleetTarget,
+    command: String,
+    run_id: String,
+    concurrency: Option<usize>,
+    pty: Option<bool>,
+    timeout_secs: Option<u64>,
+) -> Result<Vec<FleetHostResult>, String> {
+    let servers = match target {
+        FleetTarget::Servers(ids) => ids
+            .iter()
+            .map(|id| storage::get_server(id).ok_or(format!("Server not found: {}", id)))
+            .collect::<Result<Vec<_>, _>>()?,
+        FleetTarget::Tag(tag) => storage::get_all_servers()
+            .into_iter()
+            .filter(|server| server.tags.iter().any(|t| t == &tag))
+            .collect(),
+    };
+    if servers.is_empty() {
+        return Err("No servers to run on".to_string());
+    }
+    let mut options = exec_options(pty, timeout_secs);
+    options.timeout.get_or_insert(fleet::DEFAULT_TIMEOUT);
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<FleetProgress>();
+    let app_handle = app.clone();
+    tokio::spawn(async move {
+        while let Some(progress) = rx.recv().await {
+            let _ = app_handle.emit(&format!("fleet-progress-{}", run_id), progress);
+        }
+    });
+
+    Ok(fleet::run(
+        servers,
+        &command,
+        concurrency.unwrap_or(10),
+        &options,
+        Some(connect_prompter(&app)),
+        tx,
+    )
+    .await)
+}
+
+fn exec_options(pty: Option<bool>, timeout_secs: Option<u64>) -> ExecOptions {
+    ExecOptions {
+        pty: pty.unwrap_or(false),
+        timeout: timeout_secs
+            .filter(|secs| *secs > 0)
+            .map(std::time::Duration::from_secs),
+    }
+}
+
 // ============ SFTP Commands ============
 
 #[tauri::command]
-pub async fn sftp_connect(server_id: String) -> Result<String, String> {
+pub async fn sftp_connect(app: AppHandle, server_id: String) -> Result<String, String> {
     let server = storage::get_server(&server_id).ok_or("Server not found")?;
 
-    let session = SftpConnection::connect(&server)
+    let session = SftpConnection::connect(&server, Some(connect_prompter(&app)))
         .await
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| format!("{:#}", e))?;
 
     Ok(session.session_id().to_string())
 }
@@ -242,13 +693,26 @@ pub async fn sftp_write_file(
         .map_err(|e| e.to_string())
 }
 
+/// With `recursive`, a directory is deleted along with everything in it
 #[tauri::command]
-pub async fn sftp_delete(session_id: String, path: String, is_dir: bool) -> Result<(), String> {
+pub async fn sftp_delete(
+    session_id: String,
+    path: String,
+    is_dir: bool,
+    recursive: Option<bool>,
+) -> Result<(), String> {
     let session = sftp::get_sftp_session(&session_id).await.ok_or("SFTP session not found")?;
-    session
-        .delete(&path, is_dir)
-        .await
-        .map_err(|e| e.to_string())
+    if is_dir && recursive.unwrap_or(false) {
+        session
+            .remove_dir_all(&path)
+            .await
+            .map_err(|e| format!("{:#}", e))
+    } else {
+        session
+            .delete(&path, is_dir)
+            .await
+            .map_err(|e| e.to_string())
+    }
 }
 
 #[tauri::command]
@@ -290,46 +754,186 @@ pub async fn sftp_create_file(session_id: String, path: String) -> Result<(), St
         .map_err(|e| e.to_string())
 }
 
+/// Emits `sftp-progress-{transfer_id}` events for a transfer
+fn transfer_progress(app: &AppHandle) -> sftp::ProgressSink {
+    let (tx, mut rx) = mpsc::unbounded_channel::<TransferProgress>();
+    let app_handle = app.clone();
+    tokio::spawn(async move {
+        while let Some(progress) = rx.recv().await {
+            let _ = app_handle.emit(&format!("sftp-progress-{}", progress.transfer_id), progress);
+        }
+    });
+    tx
+}
+
+/// Downloads a file, or a directory with everything in it. The caller picks
+/// `transfer_id`, so it can listen for progress and cancel. `resume` says
+/// what to do with a partial file left by an earlier attempt.
 #[tauri::command]
 pub async fn sftp_download(
+    app: AppHandle,
     session_id: String,
     remote_path: String,
     local_path: String,
-) -> Result<(), String> {
+    transfer_id: String,
+    resume: Option<ResumeMode>,
+) -> Result<TransferSummary, String> {
     let session = sftp::get_sftp_session(&session_id)
         .await
         .ok_or("SFTP session not found")?;
 
-    let contents = session
-        .read_file(&remote_path)
-        .await
-        .map_err(|e| e.to_string())?;
-
-    let path = PathBuf::from(&local_path);
-    fs::write(&path, &contents)
+    let mut transfer = sftp::Transfer::new(transfer_id, Some(transfer_progress(&app)));
+    session
+        .download_path(
+            &remote_path,
+            Path::new(&local_path),
+            resume.unwrap_or_default(),
+            &mut transfer,
+        )
         .await
-        .map_err(|e| format!("Failed to write local file: {}", e))?;
-
-    Ok(())
+        .map_err(|e| format!("{:#}", e))
 }
 
+/// Uploads a file, or a directory with everything in it
 #[tauri::command]
 pub async fn sftp_upload(
+    app: AppHandle,
     session_id: String,
     local_path: String,
     remote_path: String,
-) -> Result<(), String> {
-    let path = PathBuf::from(&local_path);
-    let contents = fs::read(&path)
-        .await
-        .map_err(|e| format!("Failed to read local file: {}", e))?;
-
+    transfer_id: String,
+    resume: Option<ResumeMode>,
+) -> Result<TransferSummary, String> {
     let session = sftp::get_sftp_session(&session_id)
         .await
         .ok_or("SFTP session not found")?;
 
+    let mut transfer = sftp::Transfer::new(transfer_id, Some(transfer_progress(&app)));
     session
-        .write_file(&remote_path, &contents)
+        .upload_path(
+            Path::new(&local_path),
+            &remote_path,
+            resume.unwrap_or_default(),
+            &mut transfer,
+        )
         .await
-        .map_err(|e| e.to_string())
+        .map_err(|e| format!("{:#}", e))
+}
+
+/// Stops a download or upload; `remove_partial` deletes what it had written
+#[tauri::command]
+pub async fn sftp_cancel_transfer(
+    transfer_id: String,
+    remove_partial: Option<bool>,
+) -> Result<(), String> {
+    sftp::cancel_transfer(&transfer_id, remove_partial.unwrap_or(false)).map_err(|e| e.to_string())
+}
+
+// ============ Transfer Queue Commands ============
+
+/// Starts the queue the first time it's used, with "transfer-queue-changed"
+/// events for state changes and "transfer-queue-progress" for progress
+fn start_transfer_queue(app: &AppHandle) {
+    queue::start(|| {
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel::<TransferProgress>();
+        let (changed_tx, mut changed_rx) = mpsc::unbounded_channel::<QueuedTransfer>();
+        let app_handle = app.clone();
+        tokio::spawn(async move {
+            loop {
+                let _ = tokio::select! {
+                    Some(progress) = progress_rx.recv() => {
+                        app_handle.emit("transfer-queue-progress", progress)
+                    }
+                    Some(transfer) = changed_rx.recv() => {
+                        app_handle.emit("transfer-queue-changed", transfer)
+                    }
+                    else => break,
+                };
+            }
+        });
+        (connect_prompter(app), progress_tx, changed_tx)
+    });
+}
+
+#[tauri::command]
+pub async fn transfer_queue_list(app: AppHandle) -> TransferQueueState {
+    start_transfer_queue(&app);
+    queue::list()
+}
+
+#[tauri::command]
+pub async fn transfer_queue_add(
+    app: AppHandle,
+    transfers: Vec<TransferRequest>,
+) -> Vec<QueuedTransfer> {
+    start_transfer_queue(&app);
+    queue::add(transfers)
+}
+
+#[tauri::command]
+pub fn transfer_queue_pause() {
+    queue::pause();
+}
+
+#[tauri::command]
+pub async fn transfer_queue_resume(app: AppHandle) {
+    start_transfer_queue(&app);
+    queue::resume();
+}
+
+#[tauri::command]
+pub fn transfer_queue_remove(id: String) -> Result<(), String> {
+    queue::remove(&id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn transfer_queue_retry(id: String) -> Result<(), String> {
+    queue::retry(&id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn transfer_queue_clear_done() {
+    queue::clear_done();
+}
+
+// ============ Port Forwarding Commands ============
+
+/// Emits a "forward-connection" event for each peer using a remote forward
+fn forward_notifier(app: &AppHandle) -> forward::ForwardNotifier {
+    let (tx, mut rx) = mpsc::unbounded_channel::<ForwardConnection>();
+    let app_handle = app.clone();
+    tokio::spawn(async move {
+        while let Some(connection) = rx.recv().await {
+            let _ = app_handle.emit("forward-connection", connection);
+        }
+    });
+    tx
+}
+
+#[tauri::command]
+pub async fn start_forward(
+    app: AppHandle,
+    server_id: String,
+    forward_id: String,
+) -> Result<ForwardStatus, String> {
+    let server = storage::get_server(&server_id).ok_or("Server not found")?;
+
+    forward::start(
+        &server,
+        &forward_id,
+        Some(connect_prompter(&app)),
+        Some(forward_notifier(&app)),
+    )
+    .await
+    .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+pub async fn stop_forward(forward_id: String) -> Result<(), String> {
+    forward::stop(&forward_id).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn list_forwards() -> Vec<ForwardStatus> {
+    forward::list().await
 }
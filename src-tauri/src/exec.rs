@@ -0,0 +1,122 @@
+use crate::models::{ExecOutput, ExecResult, ExecStream, ServerConfig};
+use crate::pool;
+use crate::ssh::signal_name_of;
+use crate::transport::Prompter;
+use anyhow::{anyhow, bail, Context, Result};
+use russh::ChannelMsg;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// SSH_EXTENDED_DATA_STDERR
+const STDERR: u32 = 1;
+
+/// Receives output as it arrives instead of collecting it
+pub type OutputSink = mpsc::UnboundedSender<ExecOutput>;
+
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// Run under a PTY, for commands that insist on a terminal. Stdout and
+    /// stderr arrive merged then.
+    pub pty: bool,
+    /// Covers connecting as well as running the command
+    pub timeout: Option<Duration>,
+}
+
+/// Runs `command` on `server` without a terminal, over the pooled connection
+/// when there is one
+pub async fn run(
+    server: &ServerConfig,
+    prompter: Option<Prompter>,
+    command: &str,
+    options: &ExecOptions,
+    sink: Option<OutputSink>,
+) -> Result<ExecResult> {
+    let started = Instant::now();
+    let deadline = options
+        .timeout
+        .map(|timeout| tokio::time::Instant::from_std(started + timeout));
+    let timeout_secs = options.timeout.unwrap_or_default().as_secs();
+    let timed_out = || anyhow!("Command timed out after {}s", timeout_secs);
+
+    let start = async {
+        let connection = pool::acquire(server, prompter).await?;
+        let channel = connection
+            .open_session_channel()
+            .await
+            .context("Failed to open channel")?;
+
+        if options.pty {
+            channel
+                .request_pty(false, "xterm-256color", 80, 24, 0, 0, &[])
+                .await
+                .context("Failed to request PTY")?;
+        }
+        channel
+            .exec(true, command)
+            .await
+            .context("Failed to run command")?;
+        anyhow::Ok((connection, channel))
+    };
+    // The pool only keeps a weak reference, so the connection is held here
+    // until the command is done
+    let (_connection, mut channel) = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, start)
+            .await
+            .map_err(|_| timed_out())??,
+        None => start.await?,
+    };
+
+    let mut result = ExecResult::default();
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let collect = async {
+        while let Some(msg) = channel.wait().await {
+            let (stream, data) = match msg {
+                ChannelMsg::Data { data } => (ExecStream::Stdout, data),
+                ChannelMsg::ExtendedData { data, ext: STDERR } => (ExecStream::Stderr, data),
+                ChannelMsg::ExitStatus { exit_status } => {
+                    result.exit_code = Some(exit_status);
+                    continue;
+                }
+                ChannelMsg::ExitSignal { signal_name, .. } => {
+                    result.signal = Some(signal_name_of(&signal_name));
+                    continue;
+                }
+                ChannelMsg::Failure => bail!("Server refused to run the command"),
+                _ => continue,
+            };
+            match &sink {
+                Some(sink) => {
+                    sink.send(ExecOutput {
+                        stream,
+                        data: data.to_vec(),
+                    })
+                    .ok();
+                }
+                None if stream == ExecStream::Stdout => stdout.extend_from_slice(&data),
+                None => stderr.extend_from_slice(&data),
+            }
+        }
+        Ok(())
+    };
+
+    match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, collect).await {
+            Ok(collected) => collected?,
+            Err(_) => {
+                channel.close().await.ok();
+                return Err(timed_out());
+            }
+        },
+        None => collect.await?,
+    }
+    // Without either the output may be cut short
+    if result.exit_code.is_none() && result.signal.is_none() {
+        bail!("Connection closed before the command finished");
+    }
+
+    result.stdout = String::from_utf8_lossy(&stdout).into_owned();
+    result.stderr = String::from_utf8_lossy(&stderr).into_owned();
+    result.duration_ms = started.elapsed().as_millis() as u64;
+    Ok(result)
+}
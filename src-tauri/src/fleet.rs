@@ -0,0 +1,117 @@
+use crate::exec::{self, ExecOptions};
+use crate::models::{ExecResult, FleetHostResult, FleetHostState, FleetProgress, ServerConfig};
+use crate::transport::Prompter;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+/// Per-host timeout when the caller sets none, so one hung host can't hold
+/// up the whole run
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Runs `command` on every server, at most `concurrency` at a time. Each host
+/// connects the way a terminal would, through its proxy and jump hosts, and
+/// gets `options.timeout` for connecting and running. Results come back in
+/// the order of `servers`.
+pub async fn run(
+    servers: Vec<ServerConfig>,
+    command: &str,
+    concurrency: usize,
+    options: &ExecOptions,
+    prompter: Option<Prompter>,
+    progress: mpsc::UnboundedSender<FleetProgress>,
+) -> Vec<FleetHostResult> {
+    let total = servers.len();
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let command: Arc<str> = Arc::from(command);
+    let completed = Arc::new(AtomicUsize::new(0));
+
+    let mut hosts = JoinSet::new();
+    // The row each task reports, kept so a host whose task panics still gets one
+    let mut tasks = HashMap::new();
+    for (index, server) in servers.into_iter().enumerate() {
+        let row = FleetHostResult {
+            server_id: server.id.clone(),
+            server_name: server.name.clone(),
+            host: server.host.clone(),
+            output: ExecResult::default(),
+            error: None,
+        };
+        let permits = permits.clone();
+        let command = command.clone();
+        let options = options.clone();
+        let prompter = prompter.clone();
+        let progress = progress.clone();
+        let completed = completed.clone();
+        let task = hosts.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            progress
+                .send(FleetProgress {
+                    server_id: server.id.clone(),
+                    state: FleetHostState::Running,
+                    completed: completed.load(Ordering::Relaxed),
+                    total,
+                    result: None,
+                })
+                .ok();
+
+            let started = Instant::now();
+            let outcome = exec::run(&server, prompter, &command, &options, None).await;
+            let (output, error) = match outcome {
+                Ok(output) => (output, None),
+                Err(e) => {
+                    let output = ExecResult {
+                        duration_ms: started.elapsed().as_millis() as u64,
+                        ..Default::default()
+                    };
+                    (output, Some(format!("{:#}", e)))
+                }
+            };
+            let result = FleetHostResult {
+                server_id: server.id,
+                server_name: server.name,
+                host: server.host,
+                output,
+                error,
+            };
+            (index, result)
+        });
+        tasks.insert(task.id(), (index, row));
+    }
+
+    let mut results: Vec<Option<FleetHostResult>> = vec![None; total];
+    while let Some(joined) = hosts.join_next_with_id().await {
+        let (index, result) = match joined {
+            Ok((_, outcome)) => outcome,
+            // A panicking host fails on its own rather than sinking the run
+            Err(e) => {
+                let Some((index, mut row)) = tasks.remove(&e.id()) else {
+                    continue;
+                };
+                row.error = Some(format!("Host task failed: {}", e));
+                (index, row)
+            }
+        };
+        let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
+        let state = if result.error.is_some() {
+            FleetHostState::Failed
+        } else {
+            FleetHostState::Finished
+        };
+        progress
+            .send(FleetProgress {
+                server_id: result.server_id.clone(),
+                state,
+                completed: done,
+                total,
+                result: Some(result.clone()),
+            })
+            .ok();
+        results[index] = Some(result);
+    }
+
+    results.into_iter().flatten().collect()
+}
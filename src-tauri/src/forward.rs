@@ -0,0 +1,528 @@
+use crate::models::{
+    chrono_timestamp, DynamicForward, ForwardConnection, ForwardKind, ForwardStatus, LocalForward,
+    RemoteForward, ServerConfig,
+};
+use crate::pool::{self, PooledConnection};
+use crate::socks::{self, Reply};
+use crate::transport::Prompter;
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use russh::{client, Channel, ChannelOpenFailure};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+
+static FORWARDS: Lazy<RwLock<HashMap<String, ActiveForward>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Pause after a failed accept, which tends to fail again straight away
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(500);
+// How often a remote forward checks that its connection is still up
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Receives a notice for each peer connecting through a remote forward
+pub type ForwardNotifier = mpsc::UnboundedSender<ForwardConnection>;
+
+/// Remote forwards bound on one connection, keyed by the port the server
+/// listens on
+pub(crate) type RemoteRoutes = Arc<parking_lot::Mutex<HashMap<u32, RemoteRoute>>>;
+
+#[derive(Clone)]
+pub(crate) struct RemoteRoute {
+    forward_id: String,
+    local_host: String,
+    local_port: u16,
+    stats: Arc<ForwardStats>,
+    notifier: Option<ForwardNotifier>,
+}
+
+struct ActiveForward {
+    status: ForwardStatus,
+    stats: Arc<ForwardStats>,
+    running: Running,
+}
+
+enum Running {
+    /// Accept loop of a local or dynamic forward
+    Listener(JoinHandle<()>),
+    /// Port bound on the server for a remote forward, and the task watching
+    /// its connection
+    Remote {
+        connection: Arc<PooledConnection>,
+        address: String,
+        port: u32,
+        watcher: JoinHandle<()>,
+    },
+}
+
+impl ActiveForward {
+    fn status(&self) -> ForwardStatus {
+        ForwardStatus {
+            active_connections: self.stats.active.load(Ordering::Relaxed),
+            total_connections: self.stats.total.load(Ordering::Relaxed),
+            bytes_sent: self.stats.sent.load(Ordering::Relaxed),
+            bytes_received: self.stats.received.load(Ordering::Relaxed),
+            accept_error: self.stats.accept_error.lock().clone(),
+            ..self.status.clone()
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ForwardStats {
+    active: AtomicU64,
+    total: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+    accept_error: parking_lot::Mutex<Option<String>>,
+}
+
+/// Where a local listener sends its connections
+enum ListenTarget {
+    Fixed {
+        host: String,
+        port: u16,
+    },
+    /// Each client names its target over SOCKS5
+    Socks {
+        credentials: Option<(String, String)>,
+    },
+}
+
+/// Starts the local, remote or dynamic forward rule `forward_id` of `server`
+pub async fn start(
+    server: &ServerConfig,
+    forward_id: &str,
+    prompter: Option<Prompter>,
+    notifier: Option<ForwardNotifier>,
+) -> Result<ForwardStatus> {
+    if FORWARDS.read().await.contains_key(forward_id) {
+        bail!("Forward is already running");
+    }
+
+    let forward = if let Some(rule) = server.local_forwards.iter().find(|f| f.id == forward_id) {
+        start_local(server, rule.clone(), prompter).await?
+    } else if let Some(rule) = server.remote_forwards.iter().find(|f| f.id == forward_id) {
+        start_remote(server, rule.clone(), prompter, notifier).await?
+    } else if let Some(rule) = server.dynamic_forwards.iter().find(|f| f.id == forward_id) {
+        start_dynamic(server, rule.clone(), prompter).await?
+    } else {
+        bail!("Forward rule not found");
+    };
+
+    let status = forward.status();
+    FORWARDS
+        .write()
+        .await
+        .insert(forward_id.to_string(), forward);
+
+    Ok(status)
+}
+
+pub async fn stop(forward_id: &str) -> Result<()> {
+    let forward = FORWARDS
+        .write()
+        .await
+        .remove(forward_id)
+        .context("Forward is not running")?;
+
+    match forward.running {
+        // Dropping the accept loop closes the listener and every open tunnel
+        Running::Listener(task) => task.abort(),
+        Running::Remote {
+            connection,
+            address,
+            port,
+            watcher,
+        } => {
+            watcher.abort();
+            connection.remote_routes().lock().remove(&port);
+            connection
+                .handle()
+                .await
+                .cancel_tcpip_forward(address, port)
+                .await
+                .ok();
+        }
+    }
+    Ok(())
+}
+
+pub async fn list() -> Vec<ForwardStatus> {
+    let mut forwards: Vec<ForwardStatus> = FORWARDS
+        .read()
+        .await
+        .values()
+        .map(ActiveForward::status)
+        .collect();
+    forwards.sort_by_key(|f| f.started_at);
+    forwards
+}
+
+async fn start_local(
+    server: &ServerConfig,
+    rule: LocalForward,
+    prompter: Option<Prompter>,
+) -> Result<ActiveForward> {
+    let target = ListenTarget::Fixed {
+        host: rule.remote_host.clone(),
+        port: rule.remote_port,
+    };
+    let status = ForwardStatus {
+        forward_id: rule.id.clone(),
+        server_id: server.id.clone(),
+        kind: ForwardKind::Local,
+        bind_address: rule.bind_address.clone(),
+        bind_port: rule.local_port,
+        target_host: Some(rule.remote_host.clone()),
+        target_port: Some(rule.remote_port),
+        active_connections: 0,
+        total_connections: 0,
+        bytes_sent: 0,
+        bytes_received: 0,
+        started_at: chrono_timestamp(),
+        accept_error: None,
+    };
+    listen(server, status, target, prompter).await
+}
+
+async fn start_dynamic(
+    server: &ServerConfig,
+    rule: DynamicForward,
+    prompter: Option<Prompter>,
+) -> Result<ActiveForward> {
+    let target = ListenTarget::Socks {
+        credentials: rule
+            .username
+            .clone()
+            .filter(|u| !u.is_empty())
+            .map(|u| (u, rule.password.clone().unwrap_or_default())),
+    };
+    let status = ForwardStatus {
+        forward_id: rule.id.clone(),
+        server_id: server.id.clone(),
+        kind: ForwardKind::Dynamic,
+        bind_address: rule.bind_address.clone(),
+        bind_port: rule.local_port,
+        target_host: None,
+        target_port: None,
+        active_connections: 0,
+        total_connections: 0,
+        bytes_sent: 0,
+        bytes_received: 0,
+        started_at: chrono_timestamp(),
+        accept_error: None,
+    };
+    listen(server, status, target, prompter).await
+}
+
+/// Binds the local side of a local or dynamic forward and starts accepting
+async fn listen(
+    server: &ServerConfig,
+    status: ForwardStatus,
+    target: ListenTarget,
+    prompter: Option<Prompter>,
+) -> Result<ActiveForward> {
+    let listener = TcpListener::bind((status.bind_address.as_str(), status.bind_port))
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to listen on {}:{}",
+                status.bind_address, status.bind_port
+            )
+        })?;
+    let connection = pool::acquire(server, prompter.clone()).await?;
+
+    let stats = Arc::new(ForwardStats::default());
+    let task = tokio::spawn(accept_local(
+        listener,
+        Upstream {
+            server: server.clone(),
+            prompter,
+            connection,
+        },
+        Arc::new(target),
+        stats.clone(),
+    ));
+
+    Ok(ActiveForward {
+        status,
+        stats,
+        running: Running::Listener(task),
+    })
+}
+
+async fn start_remote(
+    server: &ServerConfig,
+    rule: RemoteForward,
+    prompter: Option<Prompter>,
+    notifier: Option<ForwardNotifier>,
+) -> Result<ActiveForward> {
+    let connection = pool::acquire(server, prompter).await?;
+
+    let allocated = connection
+        .handle_mut()
+        .await
+        .tcpip_forward(rule.bind_address.as_str(), rule.remote_port as u32)
+        .await
+        .with_context(|| {
+            format!(
+                "Server refused to listen on {}:{}",
+                rule.bind_address, rule.remote_port
+            )
+        })?;
+    // The server only reports a port when it picked one itself
+    let port = if rule.remote_port == 0 {
+        allocated
+    } else {
+        rule.remote_port as u32
+    };
+
+    let stats = Arc::new(ForwardStats::default());
+    connection.remote_routes().lock().insert(
+        port,
+        RemoteRoute {
+            forward_id: rule.id.clone(),
+            local_host: rule.local_host.clone(),
+            local_port: rule.local_port,
+            stats: stats.clone(),
+            notifier,
+        },
+    );
+
+    let status = ForwardStatus {
+        forward_id: rule.id.clone(),
+        server_id: server.id.clone(),
+        kind: ForwardKind::Remote,
+        bind_address: rule.bind_address.clone(),
+        bind_port: port as u16,
+        target_host: Some(rule.local_host.clone()),
+        target_port: Some(rule.local_port),
+        active_connections: 0,
+        total_connections: 0,
+        bytes_sent: 0,
+        bytes_received: 0,
+        started_at: chrono_timestamp(),
+        accept_error: None,
+    };
+
+    let watcher = tokio::spawn(watch_remote(connection.clone(), stats.clone()));
+    Ok(ActiveForward {
+        status,
+        stats,
+        running: Running::Remote {
+            connection,
+            address: rule.bind_address,
+            port,
+            watcher,
+        },
+    })
+}
+
+/// Marks a remote forward failed once its connection closes, which takes the
+/// port bound on the server with it
+async fn watch_remote(connection: Arc<PooledConnection>, stats: Arc<ForwardStats>) {
+    while !connection.is_closed().await {
+        tokio::time::sleep(CONNECTION_CHECK_INTERVAL).await;
+    }
+    let reason = connection
+        .close_reason()
+        .unwrap_or_else(|| "Connection closed".to_string());
+    *stats.accept_error.lock() = Some(reason);
+}
+
+/// The connection a local or dynamic forward tunnels through
+struct Upstream {
+    server: ServerConfig,
+    prompter: Option<Prompter>,
+    connection: Arc<PooledConnection>,
+}
+
+impl Upstream {
+    /// The current connection, replaced through the pool once it has closed
+    /// (a terminal that reconnected may already have a new one)
+    async fn connection(&mut self) -> Result<Arc<PooledConnection>> {
+        if self.connection.is_closed().await {
+            self.connection = pool::acquire(&self.server, self.prompter.clone())
+                .await
+                .context("Failed to reconnect")?;
+        }
+        Ok(self.connection.clone())
+    }
+}
+
+async fn accept_local(
+    listener: TcpListener,
+    mut upstream: Upstream,
+    target: Arc<ListenTarget>,
+    stats: Arc<ForwardStats>,
+) {
+    let mut tunnels = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    // E.g. out of file descriptors
+                    Err(e) => {
+                        *stats.accept_error.lock() = Some(e.to_string());
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                let connection = match upstream.connection().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        *stats.accept_error.lock() = Some(format!("{:#}", e));
+                        continue;
+                    }
+                };
+                stats.accept_error.lock().take();
+                tunnels.spawn(tunnel(
+                    socket,
+                    peer,
+                    connection,
+                    target.clone(),
+                    stats.clone(),
+                ));
+            }
+            Some(_) = tunnels.join_next() => {}
+        }
+    }
+}
+
+/// Carries one accepted local connection through a direct-tcpip channel
+async fn tunnel(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    connection: Arc<PooledConnection>,
+    target: Arc<ListenTarget>,
+    stats: Arc<ForwardStats>,
+) {
+    let (host, port) = match target.as_ref() {
+        ListenTarget::Fixed { host, port } => (host.clone(), *port),
+        ListenTarget::Socks { credentials } => {
+            let credentials = credentials.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+            match socks::accept(&mut socket, credentials).await {
+                Ok(target) => target,
+                Err(_) => return,
+            }
+        }
+    };
+    let socks = matches!(target.as_ref(), ListenTarget::Socks { .. });
+
+    let channel = connection
+        .handle()
+        .await
+        .channel_open_direct_tcpip(host, port as u32, peer.ip().to_string(), peer.port() as u32)
+        .await;
+    match channel {
+        Ok(channel) => {
+            if socks && socks::reply(&mut socket, Reply::Succeeded).await.is_err() {
+                return;
+            }
+            pipe(socket, channel.into_stream(), &stats).await;
+        }
+        // The server refused the tunnel, drop the local socket
+        Err(err) => {
+            // Anything but a refusal means the connection itself failed
+            if !matches!(err, russh::Error::ChannelOpenFailure(_)) {
+                *stats.accept_error.lock() = Some(format!("Failed to open a tunnel: {}", err));
+            }
+            if socks {
+                let reply = match err {
+                    russh::Error::ChannelOpenFailure(ChannelOpenFailure::ConnectFailed) => {
+                        Reply::ConnectionRefused
+                    }
+                    russh::Error::ChannelOpenFailure(
+                        ChannelOpenFailure::AdministrativelyProhibited,
+                    ) => Reply::NotAllowed,
+                    _ => Reply::GeneralFailure,
+                };
+                socks::reply(&mut socket, reply).await.ok();
+            }
+        }
+    }
+}
+
+/// Routes a channel the server opened for a remote forward to its local target
+pub(crate) fn accept_remote(
+    routes: &RemoteRoutes,
+    channel: Channel<client::Msg>,
+    connected_port: u32,
+    originator_address: &str,
+    originator_port: u32,
+) {
+    let route = routes.lock().get(&connected_port).cloned();
+    let Some(route) = route else {
+        // Cancelled meanwhile, or never asked for
+        tokio::spawn(async move { channel.close().await.ok() });
+        return;
+    };
+
+    if let Some(notifier) = &route.notifier {
+        notifier
+            .send(ForwardConnection {
+                forward_id: route.forward_id.clone(),
+                originator_address: originator_address.to_string(),
+                originator_port,
+            })
+            .ok();
+    }
+
+    tokio::spawn(async move {
+        match TcpStream::connect((route.local_host.as_str(), route.local_port)).await {
+            Ok(socket) => pipe(channel.into_stream(), socket, &route.stats).await,
+            Err(_) => {
+                channel.close().await.ok();
+            }
+        }
+    });
+}
+
+/// Copies both ways between a client and its target until both sides are
+/// done or either fails, keeping the byte counters up to date
+async fn pipe<C, T>(client: C, target: T, stats: &ForwardStats)
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    stats.active.fetch_add(1, Ordering::Relaxed);
+    stats.total.fetch_add(1, Ordering::Relaxed);
+
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut target_read, mut target_write) = tokio::io::split(target);
+    tokio::try_join!(
+        copy_counted(&mut client_read, &mut target_write, &stats.sent),
+        copy_counted(&mut target_read, &mut client_write, &stats.received),
+    )
+    .ok();
+
+    stats.active.fetch_sub(1, Ordering::Relaxed);
+}
+
+async fn copy_counted<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    counter: &AtomicU64,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; 32 * 1024];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buffer[..n]).await?;
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
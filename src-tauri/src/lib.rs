@@ -1,8 +1,17 @@
+mod agent_forward;
 mod commands;
+mod exec;
+mod fleet;
+mod forward;
 mod models;
+mod pool;
+mod queue;
 mod sftp;
+mod socks;
 mod ssh;
 mod storage;
+mod transport;
+mod x11;
 
 use commands::*;
 
@@ -20,11 +29,28 @@ pub fn run() {
             delete_server,
             export_servers,
             import_servers,
+            // Known hosts
+            get_known_hosts,
+            accept_host_key,
+            revoke_host_key,
+            forget_host_key,
+            import_known_hosts,
+            export_known_hosts,
+            respond_host_key,
             // SSH
+            respond_auth_prompt,
             ssh_connect,
+            ssh_open_channel,
             ssh_write,
             ssh_resize,
+            ssh_close_channel,
             ssh_disconnect,
+            ssh_exec,
+            ssh_exec_stream,
+            ssh_create_broadcast_group,
+            ssh_broadcast,
+            ssh_remove_broadcast_group,
+            fleet_run,
             // SFTP
             sftp_connect,
             sftp_list_dir,
@@ -37,6 +63,19 @@ pub fn run() {
             sftp_create_file,
             sftp_download,
             sftp_upload,
+            sftp_cancel_transfer,
+            // Transfer queue
+            transfer_queue_list,
+            transfer_queue_add,
+            transfer_queue_pause,
+            transfer_queue_resume,
+            transfer_queue_remove,
+            transfer_queue_retry,
+            transfer_queue_clear_done,
+            // Port forwarding
+            start_forward,
+            stop_forward,
+            list_forwards,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
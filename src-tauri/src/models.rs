@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,9 +10,40 @@ pub struct ServerConfig {
     pub username: String,
     pub auth: AuthMethod,
     pub proxy: Option<ProxyConfig>,
-    /// Jump host server ID - use another saved server as SSH jump host
+    /// Saved server IDs to tunnel through, in order (ProxyJump a,b,c)
+    #[serde(
+        default,
+        alias = "jump_host",
+        deserialize_with = "deserialize_jump_hosts"
+    )]
+    pub jump_hosts: Vec<String>,
     #[serde(default)]
-    pub jump_host: Option<String>,
+    pub local_forwards: Vec<LocalForward>,
+    #[serde(default)]
+    pub remote_forwards: Vec<RemoteForward>,
+    #[serde(default)]
+    pub dynamic_forwards: Vec<DynamicForward>,
+    /// Let this server use the local ssh-agent (ssh -A)
+    #[serde(default)]
+    pub forward_agent: bool,
+    /// Show X11 programs run on this server locally (ssh -X)
+    #[serde(default)]
+    pub forward_x11: bool,
+    /// Seconds of silence before probing the server, 0 disables keepalives
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u32,
+    /// Unanswered keepalives before the connection counts as dead
+    #[serde(default = "default_keepalive_max")]
+    pub keepalive_max: u32,
+    /// Reopen terminals under the same session when the connection drops
+    #[serde(default)]
+    pub auto_reconnect: bool,
+    /// Labels for picking servers as a group, e.g. in fleet runs
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Transfers the transfer queue runs on this server at once
+    #[serde(default = "default_max_transfers")]
+    pub max_transfers: u32,
     pub notes: Option<String>,
     #[serde(default)]
     pub created_at: i64,
@@ -37,7 +68,17 @@ impl ServerConfig {
             username,
             auth,
             proxy: None,
-            jump_host: None,
+            jump_hosts: Vec::new(),
+            local_forwards: Vec::new(),
+            remote_forwards: Vec::new(),
+            dynamic_forwards: Vec::new(),
+            forward_agent: false,
+            forward_x11: false,
+            keepalive_interval: default_keepalive_interval(),
+            keepalive_max: default_keepalive_max(),
+            auto_reconnect: false,
+            tags: Vec::new(),
+            max_transfers: default_max_transfers(),
             notes: None,
             created_at: now,
             updated_at: now,
@@ -45,7 +86,38 @@ impl ServerConfig {
     }
 }
 
-fn chrono_timestamp() -> i64 {
+/// Accepts the single jump host ID stored by older versions as well as a list
+pub(crate) fn deserialize_jump_hosts<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum JumpHosts {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Option::<JumpHosts>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(JumpHosts::One(id)) => vec![id],
+        Some(JumpHosts::Many(ids)) => ids,
+    })
+}
+
+pub fn default_keepalive_interval() -> u32 {
+    30
+}
+
+pub fn default_keepalive_max() -> u32 {
+    3
+}
+
+pub fn default_max_transfers() -> u32 {
+    3
+}
+
+pub fn chrono_timestamp() -> i64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_default()
@@ -59,7 +131,18 @@ pub enum AuthMethod {
     PrivateKey {
         key: String,
         passphrase: Option<String>,
+        /// OpenSSH certificate (`*-cert.pub`) signed for this key
+        #[serde(default)]
+        certificate: Option<String>,
     },
+    /// Use the identities held by a running ssh-agent
+    Agent {
+        /// Only offer the identity with this SHA256 fingerprint
+        #[serde(default)]
+        fingerprint: Option<String>,
+    },
+    /// Answer the server's prompts (PAM, OTP...) when connecting
+    KeyboardInteractive,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -78,6 +161,201 @@ pub enum ProxyType {
     Socks5,
 }
 
+/// A local port forward, like `ssh -L bind_address:local_port:remote_host:remote_port`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalForward {
+    #[serde(default)]
+    pub id: String,
+    pub bind_address: String,
+    pub local_port: u16,
+    pub remote_host: String,
+    pub remote_port: u16,
+}
+
+/// A remote port forward, like `ssh -R bind_address:remote_port:local_host:local_port`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteForward {
+    #[serde(default)]
+    pub id: String,
+    /// Address the server listens on; empty or `localhost` keeps it private
+    pub bind_address: String,
+    /// 0 lets the server pick a free port
+    pub remote_port: u16,
+    pub local_host: String,
+    pub local_port: u16,
+}
+
+/// A dynamic forward, like `ssh -D bind_address:local_port`: a local SOCKS5
+/// proxy whose connections are opened from the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DynamicForward {
+    #[serde(default)]
+    pub id: String,
+    pub bind_address: String,
+    pub local_port: u16,
+    /// SOCKS5 clients must log in with these when set
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardKind {
+    Local,
+    Remote,
+    Dynamic,
+}
+
+/// A running port forward and its traffic so far
+#[derive(Debug, Clone, Serialize)]
+pub struct ForwardStatus {
+    pub forward_id: String,
+    pub server_id: String,
+    pub kind: ForwardKind,
+    pub bind_address: String,
+    pub bind_port: u16,
+    /// None for dynamic forwards, where each client picks its own target
+    pub target_host: Option<String>,
+    pub target_port: Option<u16>,
+    pub active_connections: u64,
+    pub total_connections: u64,
+    /// Bytes sent towards the target and received back from it
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub started_at: i64,
+    /// Why the last incoming connection could not be accepted, cleared once
+    /// one is again
+    pub accept_error: Option<String>,
+}
+
+/// Sent to the frontend when a peer connects through a remote forward
+#[derive(Debug, Clone, Serialize)]
+pub struct ForwardConnection {
+    pub forward_id: String,
+    pub originator_address: String,
+    pub originator_port: u32,
+}
+
+/// A trusted SSH host key, keyed by host, port and key type
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownHost {
+    /// Host name, or the raw OpenSSH host field when `pattern` is set
+    pub host: String,
+    /// Port of a plain host entry, unused for pattern entries
+    pub port: u16,
+    pub key_type: String,
+    /// OpenSSH style fingerprint, e.g. `SHA256:...`
+    pub fingerprint: String,
+    /// Base64 encoded public key blob
+    pub public_key: String,
+    #[serde(default)]
+    pub marker: Option<HostKeyMarker>,
+    /// Imported entries may name hosts by wildcard (`*.example.com`) or by a
+    /// hashed name (`|1|salt|hash`) instead of a plain host
+    #[serde(default)]
+    pub pattern: bool,
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub added_at: i64,
+}
+
+/// OpenSSH known_hosts line markers
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyMarker {
+    /// `@cert-authority`: the key is a CA trusted to sign host certificates
+    CertAuthority,
+    /// `@revoked`: the key must never be accepted
+    Revoked,
+}
+
+/// Outcome of importing a known_hosts file
+#[derive(Debug, Clone, Serialize)]
+pub struct KnownHostsImport {
+    pub imported: usize,
+    /// Lines that could not be read, e.g. with an unsupported marker
+    pub skipped: Vec<SkippedLine>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedLine {
+    /// 1-based line number
+    pub line: usize,
+    pub error: String,
+}
+
+/// Summary of an OpenSSH user certificate, shown before connecting
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateInfo {
+    pub key_id: String,
+    pub principals: Vec<String>,
+    /// Unix timestamps, None when the certificate has no bound on that side
+    pub valid_after: Option<u64>,
+    pub valid_before: Option<u64>,
+    pub expired: bool,
+}
+
+/// Sent to the frontend when a server presents an unknown or changed host key
+#[derive(Debug, Clone, Serialize)]
+pub struct HostKeyPrompt {
+    pub prompt_id: String,
+    pub host: String,
+    pub port: u16,
+    pub key_type: String,
+    pub fingerprint: String,
+    /// Keys stored for this host, set when it presents another one. Keys of
+    /// the presented type come first.
+    pub previous_keys: Vec<StoredHostKey>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredHostKey {
+    pub key_type: String,
+    pub fingerprint: String,
+}
+
+/// Sent to the frontend each time a server has the forwarded agent sign
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentForwardUse {
+    pub server_id: String,
+    pub server_name: String,
+    pub host: String,
+    /// SHA256 fingerprint of the key asked to sign, None if it was not a
+    /// plain public key
+    pub fingerprint: Option<String>,
+    pub used_at: i64,
+}
+
+/// Sent to the frontend for each round of keyboard-interactive prompts
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthPrompt {
+    pub prompt_id: String,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub name: String,
+    pub instructions: String,
+    pub prompts: Vec<AuthPromptField>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthPromptField {
+    pub prompt: String,
+    /// Whether the answer may be shown while typing
+    pub echo: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyDecision {
+    AcceptOnce,
+    AcceptAndSave,
+    Reject,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEntry {
     pub name: String,
@@ -88,12 +366,270 @@ pub struct FileEntry {
     pub permissions: String,
 }
 
+/// Whether a download or upload carries on from a partial file left by an
+/// earlier attempt
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResumeMode {
+    #[default]
+    Restart,
+    /// Continue from the partial file's size, no questions asked
+    Append,
+    /// Continue only if the source still has the size and mtime it had when
+    /// the partial file was started, otherwise start over
+    Verify,
+}
+
+/// Size and mtime of a transfer's source, to tell whether it changed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct SourceStamp {
+    pub size: u64,
+    pub mtime: i64,
+}
+
+/// What is known about the partial file of an unfinished transfer
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct PartialTransfer {
+    /// The source when the transfer started writing
+    #[serde(flatten)]
+    pub source: SourceStamp,
+    /// How far from the start the file was written without gaps. Chunks can
+    /// land out of order, so the file may be longer than this and still have
+    /// holes before its end.
+    #[serde(default)]
+    pub written: u64,
+}
+
+/// Sent to the frontend as `sftp-progress-{transfer_id}` while a file or
+/// directory is downloaded or uploaded
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferProgress {
+    pub transfer_id: String,
+    pub bytes_done: u64,
+    /// None when the server doesn't say how big the file is
+    pub bytes_total: Option<u64>,
+    /// Averaged over the whole transfer
+    pub bytes_per_sec: u64,
+    pub eta_secs: Option<u64>,
+    /// Files finished, successfully or not, out of a directory's files
+    pub files_done: usize,
+    pub files_total: usize,
+}
+
+/// A file or directory a transfer couldn't copy, by its path relative to the
+/// directory being transferred
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferFailure {
+    pub path: String,
+    pub error: String,
+}
+
+/// What a download or upload copied. A directory transfer skips files that
+/// fail and lists them in `failures`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransferSummary {
+    pub files: usize,
+    pub bytes: u64,
+    pub failures: Vec<TransferFailure>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferDirection {
+    Download,
+    Upload,
+}
+
+/// A file or directory to put in the transfer queue
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransferRequest {
+    pub server_id: String,
+    pub direction: TransferDirection,
+    pub remote_path: String,
+    pub local_path: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuedTransferState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+/// A transfer in the queue. Sent to the frontend as "transfer-queue-changed"
+/// whenever its state changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedTransfer {
+    pub id: String,
+    pub server_id: String,
+    pub direction: TransferDirection,
+    pub remote_path: String,
+    pub local_path: String,
+    pub state: QueuedTransferState,
+    /// Failed attempts so far
+    pub attempts: u32,
+    /// When a pending transfer that failed is retried, in unix seconds
+    pub retry_at: Option<i64>,
+    /// Why the last attempt failed
+    pub error: Option<String>,
+    pub summary: Option<TransferSummary>,
+    pub created_at: i64,
+}
+
+impl QueuedTransfer {
+    pub fn new(request: TransferRequest) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            server_id: request.server_id,
+            direction: request.direction,
+            remote_path: request.remote_path,
+            local_path: request.local_path,
+            state: QueuedTransferState::Pending,
+            attempts: 0,
+            retry_at: None,
+            error: None,
+            summary: None,
+            created_at: chrono_timestamp(),
+        }
+    }
+}
+
+/// The whole transfer queue, as saved and as listed for the transfer panel
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransferQueueState {
+    pub paused: bool,
+    pub transfers: Vec<QueuedTransfer>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TerminalSize {
     pub cols: u32,
     pub rows: u32,
 }
 
+/// Where a terminal's connection is at
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionState {
+    Connecting,
+    Connected,
+    Reconnecting,
+    Closed,
+}
+
+/// Sent to the frontend as `ssh-state-{session_id}` when a terminal's
+/// connection changes
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionStateChange {
+    pub state: SessionState,
+    /// Why the connection dropped or closed
+    pub reason: Option<String>,
+    /// Reconnect attempt, starting at 1
+    pub attempt: Option<u32>,
+    /// Seconds until the next attempt while reconnecting
+    pub retry_in: Option<u64>,
+}
+
+/// Sent to the frontend as `ssh-exit-{session_id}` when the remote shell ends
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ShellExit {
+    pub exit_code: Option<u32>,
+    /// Signal name without the SIG prefix, when the shell was killed
+    pub signal: Option<String>,
+    pub core_dumped: bool,
+    pub error_message: Option<String>,
+}
+
+/// What a command run with `ssh_exec` did
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExecResult {
+    pub exit_code: Option<u32>,
+    /// Signal name without the SIG prefix, when the command was killed
+    pub signal: Option<String>,
+    /// Lossy UTF-8; left empty when the output was streamed instead
+    pub stdout: String,
+    pub stderr: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecStream {
+    Stdout,
+    Stderr,
+}
+
+/// Sent to the frontend as `ssh-exec-{exec_id}` for each chunk of output of a
+/// streamed command
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecOutput {
+    pub stream: ExecStream,
+    pub data: Vec<u8>,
+}
+
+/// Which saved servers a fleet run covers
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum FleetTarget {
+    Servers(Vec<String>),
+    /// Every server carrying this tag
+    Tag(String),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FleetHostState {
+    Running,
+    /// The command ran to the end, whatever its exit code
+    Finished,
+    /// Connecting or running failed, or the host timed out
+    Failed,
+}
+
+/// One row of a fleet run's results
+#[derive(Debug, Clone, Serialize)]
+pub struct FleetHostResult {
+    pub server_id: String,
+    pub server_name: String,
+    pub host: String,
+    #[serde(flatten)]
+    pub output: ExecResult,
+    /// Why the host failed; its output is empty then
+    pub error: Option<String>,
+}
+
+/// Sent to the frontend as `fleet-progress-{run_id}` when a host starts or
+/// finishes
+#[derive(Debug, Clone, Serialize)]
+pub struct FleetProgress {
+    pub server_id: String,
+    pub state: FleetHostState,
+    /// Hosts done so far, out of `total`
+    pub completed: usize,
+    pub total: usize,
+    /// Set once the host is done
+    pub result: Option<FleetHostResult>,
+}
+
+/// A terminal in a broadcast group; the channel defaults to the session's
+/// first shell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BroadcastMember {
+    pub session_id: String,
+    #[serde(default)]
+    pub channel_id: Option<String>,
+}
+
+/// A member a broadcast write could not reach
+#[derive(Debug, Clone, Serialize)]
+pub struct BroadcastFailure {
+    pub session_id: String,
+    pub channel_id: Option<String>,
+    pub error: String,
+}
+
 impl Default for TerminalSize {
     fn default() -> Self {
         Self { cols: 80, rows: 24 }
@@ -0,0 +1,243 @@
+use crate::agent_forward::{self, AgentOrigin};
+use crate::forward::{self, RemoteRoutes};
+use crate::models::ServerConfig;
+use crate::transport::{self, HostKeyCheck, Prompter};
+use crate::x11::{self, X11Displays};
+use anyhow::Result;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use russh::keys::*;
+use russh::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use tokio::sync::{Mutex, RwLock, RwLockMappedWriteGuard, RwLockReadGuard, RwLockWriteGuard};
+
+// One slot per server. The slot stays locked while connecting so that
+// terminals and SFTP opened at the same time share a single handshake.
+type Slot = Arc<Mutex<Weak<PooledConnection>>>;
+
+static POOL: Lazy<parking_lot::Mutex<HashMap<String, Slot>>> =
+    Lazy::new(|| parking_lot::Mutex::new(HashMap::new()));
+
+/// An authenticated connection shared by every terminal and SFTP session on
+/// one server. Each user holds an `Arc`; the connection is closed when the
+/// last one is dropped.
+pub struct PooledConnection {
+    // Opening channels only needs a shared handle; global requests such as
+    // tcpip-forward need it exclusively
+    connection: RwLock<Option<transport::Connection<ClientHandler>>>,
+    remote_routes: RemoteRoutes,
+    x11_displays: X11Displays,
+    close_reason: CloseReason,
+}
+
+// Why the session ended, set by the handler as the connection goes away
+type CloseReason = Arc<parking_lot::Mutex<Option<String>>>;
+
+impl PooledConnection {
+    pub async fn handle(&self) -> RwLockReadGuard<'_, client::Handle<ClientHandler>> {
+        RwLockReadGuard::map(self.connection.read().await, |connection| {
+            &connection
+                .as_ref()
+                .expect("connection is only taken on drop")
+                .handle
+        })
+    }
+
+    pub async fn handle_mut(&self) -> RwLockMappedWriteGuard<'_, client::Handle<ClientHandler>> {
+        RwLockWriteGuard::map(self.connection.write().await, |connection| {
+            &mut connection
+                .as_mut()
+                .expect("connection is only taken on drop")
+                .handle
+        })
+    }
+
+    pub async fn open_session_channel(&self) -> Result<Channel<client::Msg>> {
+        Ok(self.handle().await.channel_open_session().await?)
+    }
+
+    /// Remote forwards bound on this connection
+    pub fn remote_routes(&self) -> &RemoteRoutes {
+        &self.remote_routes
+    }
+
+    /// Displays handed out to shells that asked for X11 forwarding
+    pub fn x11_displays(&self) -> &X11Displays {
+        &self.x11_displays
+    }
+
+    pub async fn is_closed(&self) -> bool {
+        self.handle().await.is_closed()
+    }
+
+    /// Why the connection ended, if it has and we know
+    pub fn close_reason(&self) -> Option<String> {
+        self.close_reason.lock().clone()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let Some(connection) = self.connection.get_mut().take() else {
+            return;
+        };
+        // Say goodbye politely when a runtime is around, otherwise dropping
+        // the handle is enough to end the session
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                connection
+                    .handle
+                    .disconnect(Disconnect::ByApplication, "", "en")
+                    .await
+                    .ok();
+            });
+        }
+    }
+}
+
+/// Returns the live connection to `server`, connecting first if there is none
+pub async fn acquire(
+    server: &ServerConfig,
+    prompter: Option<Prompter>,
+) -> Result<Arc<PooledConnection>> {
+    let slot = POOL.lock().entry(server.id.clone()).or_default().clone();
+    let mut pooled = slot.lock().await;
+
+    if let Some(connection) = pooled.upgrade() {
+        if !connection.is_closed().await {
+            return Ok(connection);
+        }
+    }
+
+    let remote_routes = RemoteRoutes::default();
+    let handler_routes = remote_routes.clone();
+    let x11_displays = X11Displays::default();
+    let handler_displays = x11_displays.clone();
+    let close_reason = CloseReason::default();
+    let handler_close_reason = close_reason.clone();
+    let agent_origin = server.forward_agent.then(|| AgentOrigin::new(server));
+    let handler_prompter = prompter.clone();
+    let connection = transport::connect(server, prompter, |host_key| ClientHandler {
+        host_key,
+        remote_routes: handler_routes,
+        x11_displays: handler_displays,
+        agent_origin,
+        prompter: handler_prompter,
+        close_reason: handler_close_reason,
+    })
+    .await?;
+    let connection = Arc::new(PooledConnection {
+        connection: RwLock::new(Some(connection)),
+        remote_routes,
+        x11_displays,
+        close_reason,
+    });
+    *pooled = Arc::downgrade(&connection);
+
+    Ok(connection)
+}
+
+/// Stops handing out the current connection to a server, e.g. after its
+/// settings changed. Sessions already using it keep it until they close.
+pub fn evict(server_id: &str) {
+    POOL.lock().remove(server_id);
+}
+
+pub struct ClientHandler {
+    host_key: HostKeyCheck,
+    remote_routes: RemoteRoutes,
+    x11_displays: X11Displays,
+    /// Set when the server may use our agent
+    agent_origin: Option<AgentOrigin>,
+    prompter: Option<Prompter>,
+    close_reason: CloseReason,
+}
+
+#[async_trait]
+impl client::Handler for ClientHandler {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        self.host_key.verify(server_public_key).await
+    }
+
+    async fn disconnected(
+        &mut self,
+        reason: client::DisconnectReason<Self::Error>,
+    ) -> Result<(), Self::Error> {
+        let (message, result) = match reason {
+            client::DisconnectReason::ReceivedDisconnect(info) if info.message.is_empty() => {
+                ("Server closed the connection".to_string(), Ok(()))
+            }
+            client::DisconnectReason::ReceivedDisconnect(info) => (
+                format!("Server closed the connection: {}", info.message),
+                Ok(()),
+            ),
+            client::DisconnectReason::Error(e) => {
+                let message = match e.downcast_ref::<russh::Error>() {
+                    Some(russh::Error::KeepaliveTimeout) => {
+                        "Server stopped answering keepalives".to_string()
+                    }
+                    _ => format!("Connection lost: {}", e),
+                };
+                (message, Err(e))
+            }
+        };
+        *self.close_reason.lock() = Some(message);
+        result
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<client::Msg>,
+        _connected_address: &str,
+        connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        forward::accept_remote(
+            &self.remote_routes,
+            channel,
+            connected_port,
+            originator_address,
+            originator_port,
+        );
+        Ok(())
+    }
+
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: Channel<client::Msg>,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        match &self.agent_origin {
+            Some(origin) => agent_forward::accept(channel, origin.clone(), self.prompter.clone()),
+            // Never asked for, don't hand out our keys
+            None => {
+                tokio::spawn(async move { channel.close().await.ok() });
+            }
+        }
+        Ok(())
+    }
+
+    async fn server_channel_open_x11(
+        &mut self,
+        channel: Channel<client::Msg>,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        if self.x11_displays.lock().is_empty() {
+            // No shell asked for X11
+            tokio::spawn(async move { channel.close().await.ok() });
+        } else {
+            x11::accept(channel, self.x11_displays.clone());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+use crate::models::{
+    chrono_timestamp, QueuedTransfer, QueuedTransferState, ResumeMode, TransferDirection,
+    TransferQueueState, TransferRequest, TransferSummary,
+};
+use crate::sftp::{self, SftpConnection};
+use crate::storage;
+use crate::transport::Prompter;
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify, OnceCell};
+
+// Failed attempts before a transfer is left failed
+const MAX_ATTEMPTS: u32 = 5;
+// Retries wait 5s, 10s, 20s, ... but never more than 5 minutes
+const RETRY_DELAY_SECS: i64 = 5;
+const MAX_RETRY_DELAY_SECS: i64 = 300;
+
+static QUEUE: Lazy<Mutex<Queue>> = Lazy::new(|| Mutex::new(Queue::load()));
+
+// Wakes the scheduler when a transfer may be ready to start
+static WAKE: Lazy<Notify> = Lazy::new(Notify::new);
+
+// Filled in by the first transfer to need the connection
+type ConnectionCell = Arc<OnceCell<Arc<SftpConnection>>>;
+
+// One SFTP connection per server, shared by its transfers
+static CONNECTIONS: Lazy<Mutex<HashMap<String, ConnectionCell>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Receives transfers whose state changed
+pub type QueueNotifier = mpsc::UnboundedSender<QueuedTransfer>;
+
+#[derive(Clone)]
+struct Runner {
+    prompter: Prompter,
+    progress: sftp::ProgressSink,
+    notifier: QueueNotifier,
+}
+
+struct Queue {
+    paused: bool,
+    transfers: Vec<QueuedTransfer>,
+    // Running transfers cancelled by pausing, which go back to pending
+    pausing: HashSet<String>,
+    // Set once the queue is started
+    runner: Option<Runner>,
+}
+
+impl Queue {
+    fn load() -> Self {
+        let saved = storage::load_transfer_queue()
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let mut transfers = saved.transfers;
+        // Whatever was running when the app closed carries on from there
+        for transfer in &mut transfers {
+            if transfer.state == QueuedTransferState::Running {
+                transfer.state = QueuedTransferState::Pending;
+            }
+        }
+
+        Self {
+            paused: saved.paused,
+            transfers,
+            pausing: HashSet::new(),
+            runner: None,
+        }
+    }
+
+    fn state(&self) -> TransferQueueState {
+        TransferQueueState {
+            paused: self.paused,
+            transfers: self.transfers.clone(),
+        }
+    }
+
+    fn save(&self) {
+        storage::save_transfer_queue(&self.state()).ok();
+    }
+
+    fn notify(&self, transfer: &QueuedTransfer) {
+        if let Some(runner) = &self.runner {
+            runner.notifier.send(transfer.clone()).ok();
+        }
+    }
+}
+
+/// Starts running the queue, including transfers left in it when the app
+/// last closed, with the prompter and senders `setup` returns. Later calls do
+/// nothing, without calling `setup`.
+pub fn start(setup: impl FnOnce() -> (Prompter, sftp::ProgressSink, QueueNotifier)) {
+    let mut queue = QUEUE.lock();
+    if queue.runner.is_some() {
+        return;
+    }
+    let (prompter, progress, notifier) = setup();
+    queue.runner = Some(Runner {
+        prompter,
+        progress,
+        notifier,
+    });
+    tokio::spawn(run_scheduler());
+}
+
+pub fn list() -> TransferQueueState {
+    QUEUE.lock().state()
+}
+
+pub fn add(requests: Vec<TransferRequest>) -> Vec<QueuedTransfer> {
+    let added: Vec<QueuedTransfer> = requests.into_iter().map(QueuedTransfer::new).collect();
+
+    let mut queue = QUEUE.lock();
+    queue.transfers.extend(added.iter().cloned());
+    queue.save();
+    for transfer in &added {
+        queue.notify(transfer);
+    }
+    WAKE.notify_one();
+    added
+}
+
+/// Stops starting transfers. Running ones are cancelled and go back to
+/// pending, to carry on from where they stopped once the queue is resumed.
+pub fn pause() {
+    let mut queue = QUEUE.lock();
+    queue.paused = true;
+    let running: Vec<String> = queue
+        .transfers
+        .iter()
+        .filter(|transfer| transfer.state == QueuedTransferState::Running)
+        .map(|transfer| transfer.id.clone())
+        .collect();
+    for id in running {
+        sftp::cancel_transfer(&id, false).ok();
+        queue.pausing.insert(id);
+    }
+    queue.save();
+}
+
+pub fn resume() {
+    let mut queue = QUEUE.lock();
+    queue.paused = false;
+    queue.save();
+    WAKE.notify_one();
+}
+
+/// Takes a transfer out of the queue, cancelling it if it is running
+pub fn remove(id: &str) -> Result<()> {
+    let mut queue = QUEUE.lock();
+    let index = queue
+        .transfers
+        .iter()
+        .position(|transfer| transfer.id == id)
+        .ok_or_else(|| anyhow!("Transfer not found"))?;
+    let transfer = queue.transfers.remove(index);
+    if transfer.state == QueuedTransferState::Running {
+        sftp::cancel_transfer(id, false).ok();
+    }
+    queue.save();
+    // It may have been the last one to need its server's connection
+    WAKE.notify_one();
+    Ok(())
+}
+
+/// Puts a failed transfer back in line, with a fresh set of attempts
+pub fn retry(id: &str) -> Result<()> {
+    let mut queue = QUEUE.lock();
+    let transfer = queue
+        .transfers
+        .iter_mut()
+        .find(|transfer| transfer.id == id)
+        .ok_or_else(|| anyhow!("Transfer not found"))?;
+    if transfer.state != QueuedTransferState::Failed {
+        return Err(anyhow!("Only failed transfers can be retried"));
+    }
+    transfer.state = QueuedTransferState::Pending;
+    transfer.attempts = 0;
+    transfer.retry_at = None;
+
+    let transfer = transfer.clone();
+    queue.save();
+    queue.notify(&transfer);
+    WAKE.notify_one();
+    Ok(())
+}
+
+/// Removes the transfers that are done
+pub fn clear_done() {
+    let mut queue = QUEUE.lock();
+    queue
+        .transfers
+        .retain(|transfer| transfer.state != QueuedTransferState::Done);
+    queue.save();
+}
+
+async fn run_scheduler() {
+    loop {
+        let next_retry = schedule();
+        let retry_due = async {
+            match next_retry {
+                Some(at) => {
+                    let wait = (at - chrono_timestamp()).max(1) as u64;
+                    tokio::time::sleep(Duration::from_secs(wait)).await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = WAKE.notified() => {}
+            _ = retry_due => {}
+        }
+    }
+}
+
+/// Starts pending transfers, as many as each server allows at once. Returns
+/// when the next retry is due, if one is waiting.
+fn schedule() -> Option<i64> {
+    let mut queue = QUEUE.lock();
+    disconnect_idle(&queue);
+    let runner = match &queue.runner {
+        Some(runner) if !queue.paused => runner.clone(),
+        _ => return None,
+    };
+
+    let mut running: HashMap<String, usize> = HashMap::new();
+    for transfer in &queue.transfers {
+        if transfer.state == QueuedTransferState::Running {
+            *running.entry(transfer.server_id.clone()).or_default() += 1;
+        }
+    }
+
+    let now = chrono_timestamp();
+    let mut limits: HashMap<String, Option<usize>> = HashMap::new();
+    let mut next_retry: Option<i64> = None;
+    let mut changed = Vec::new();
+    for transfer in &mut queue.transfers {
+        if transfer.state != QueuedTransferState::Pending {
+            continue;
+        }
+        if let Some(retry_at) = transfer.retry_at.filter(|at| *at > now) {
+            next_retry = Some(next_retry.map_or(retry_at, |next| next.min(retry_at)));
+            continue;
+        }
+
+        let limit = *limits.entry(transfer.server_id.clone()).or_insert_with(|| {
+            storage::get_server(&transfer.server_id)
+                .map(|server| server.max_transfers.max(1) as usize)
+        });
+        let Some(limit) = limit else {
+            transfer.state = QueuedTransferState::Failed;
+            transfer.error = Some("Server not found".to_string());
+            changed.push(transfer.clone());
+            continue;
+        };
+        let count = running.entry(transfer.server_id.clone()).or_default();
+        if *count >= limit {
+            continue;
+        }
+        *count += 1;
+
+        transfer.state = QueuedTransferState::Running;
+        transfer.retry_at = None;
+        changed.push(transfer.clone());
+        // Registered right away, so pausing can cancel it while it connects
+        let handle = sftp::Transfer::new(transfer.id.clone(), Some(runner.progress.clone()));
+        tokio::spawn(run(transfer.clone(), handle, runner.clone()));
+    }
+
+    if !changed.is_empty() {
+        queue.save();
+        for transfer in &changed {
+            queue.notify(transfer);
+        }
+    }
+    next_retry
+}
+
+async fn run(transfer: QueuedTransfer, mut handle: sftp::Transfer, runner: Runner) {
+    let result = attempt(&transfer, &mut handle, &runner.prompter).await;
+    drop(handle);
+    finish(&transfer.id, result);
+    WAKE.notify_one();
+}
+
+async fn attempt(
+    transfer: &QueuedTransfer,
+    handle: &mut sftp::Transfer,
+    prompter: &Prompter,
+) -> Result<TransferSummary> {
+    let sftp = connection(&transfer.server_id, prompter).await?;
+    // A retry carries on from what earlier attempts wrote, unless the source
+    // has changed since
+    let resume = ResumeMode::Verify;
+    let local_path = Path::new(&transfer.local_path);
+    match transfer.direction {
+        TransferDirection::Download => {
+            sftp.download_path(&transfer.remote_path, local_path, resume, handle)
+                .await
+        }
+        TransferDirection::Upload => {
+            sftp.upload_path(local_path, &transfer.remote_path, resume, handle)
+                .await
+        }
+    }
+}
+
+fn finish(id: &str, result: Result<TransferSummary>) {
+    let mut queue = QUEUE.lock();
+    let paused = queue.pausing.remove(id);
+    // Gone if it was removed while running
+    let Some(transfer) = queue
+        .transfers
+        .iter_mut()
+        .find(|transfer| transfer.id == id)
+    else {
+        return;
+    };
+
+    match result {
+        Ok(summary) => {
+            transfer.state = QueuedTransferState::Done;
+            transfer.error = None;
+            transfer.summary = Some(summary);
+        }
+        // Paused, so it waits its turn again
+        Err(e) if e.is::<sftp::Cancelled>() && paused => {
+            transfer.state = QueuedTransferState::Pending;
+        }
+        // Cancelled by ID, it stays put until retried
+        Err(e) if e.is::<sftp::Cancelled>() => {
+            transfer.state = QueuedTransferState::Failed;
+            transfer.error = Some(e.to_string());
+        }
+        Err(e) => {
+            transfer.attempts += 1;
+            transfer.error = Some(format!("{:#}", e));
+            if transfer.attempts < MAX_ATTEMPTS {
+                transfer.state = QueuedTransferState::Pending;
+                transfer.retry_at = Some(chrono_timestamp() + retry_delay(transfer.attempts));
+            } else {
+                transfer.state = QueuedTransferState::Failed;
+            }
+        }
+    }
+
+    let transfer = transfer.clone();
+    queue.save();
+    queue.notify(&transfer);
+}
+
+/// Seconds to wait before the next attempt, doubling with each failure
+fn retry_delay(attempts: u32) -> i64 {
+    let doublings = attempts.saturating_sub(1).min(16);
+    (RETRY_DELAY_SECS << doublings).min(MAX_RETRY_DELAY_SECS)
+}
+
+/// Closes the connections of servers that no transfer is waiting for or
+/// using any more
+fn disconnect_idle(queue: &Queue) {
+    let mut connections = CONNECTIONS.lock();
+    let idle: Vec<String> = connections
+        .keys()
+        .filter(|server_id| {
+            !queue.transfers.iter().any(|transfer| {
+                &transfer.server_id == *server_id
+                    && matches!(
+                        transfer.state,
+                        QueuedTransferState::Pending | QueuedTransferState::Running
+                    )
+            })
+        })
+        .cloned()
+        .collect();
+    for server_id in idle {
+        let cell = connections.remove(&server_id);
+        if let Some(sftp) = cell.and_then(|cell| cell.get().cloned()) {
+            tokio::spawn(async move { sftp.close().await.ok() });
+        }
+    }
+}
+
+/// The server's shared SFTP connection, reopened if it has dropped
+async fn connection(server_id: &str, prompter: &Prompter) -> Result<Arc<SftpConnection>> {
+    let mut cell = CONNECTIONS
+        .lock()
+        .entry(server_id.to_string())
+        .or_default()
+        .clone();
+
+    if let Some(sftp) = cell.get().cloned() {
+        if !sftp.is_closed().await {
+            return Ok(sftp);
+        }
+        // Replace it, unless another transfer already has
+        cell = {
+            let mut connections = CONNECTIONS.lock();
+            let current = connections.entry(server_id.to_string()).or_default();
+            if Arc::ptr_eq(current, &cell) {
+                *current = Arc::default();
+            }
+            current.clone()
+        };
+        sftp.close().await.ok();
+    }
+
+    let sftp = cell
+        .get_or_try_init(|| async {
+            let server =
+                storage::get_server(server_id).ok_or_else(|| anyhow!("Server not found"))?;
+            SftpConnection::connect(&server, Some(prompter.clone())).await
+        })
+        .await?;
+    Ok(sftp.clone())
+}
@@ -1,73 +1,214 @@
-use crate::models::{AuthMethod, FileEntry, ProxyConfig, ProxyType, ServerConfig};
+use crate::models::{
+    FileEntry, PartialTransfer, ResumeMode, ServerConfig, SourceStamp, TransferFailure,
+    TransferProgress, TransferSummary,
+};
+use crate::pool::{self, PooledConnection};
 use crate::storage;
-use anyhow::{Context, Result};
-use async_trait::async_trait;
+use crate::transport::Prompter;
+use anyhow::{anyhow, Context, Result};
 use once_cell::sync::Lazy;
-use russh::keys::*;
-use russh::*;
-use russh_sftp::client::SftpSession;
-use std::collections::HashMap;
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::client::{RawSftpSession, SftpSession};
+use russh_sftp::protocol::{FileAttributes, OpenFlags, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tokio_socks::tcp::Socks5Stream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch, OnceCell, RwLock};
+use tokio::task::JoinSet;
 
 static SFTP_SESSIONS: Lazy<RwLock<HashMap<String, Arc<SftpConnection>>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 
-// Store jump host connections to keep them alive
-static SFTP_JUMP_CONNECTIONS: Lazy<RwLock<HashMap<String, Arc<SftpJumpHostConnection>>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
-
-struct SftpJumpHostConnection {
-    #[allow(dead_code)]
-    handle: client::Handle<JumpHostHandler>,
+// Downloads and uploads move files in requests of this size, keeping up to
+// MAX_IN_FLIGHT of them outstanding (what OpenSSH's sftp does by default)
+const CHUNK_SIZE: u32 = 32 * 1024;
+const MAX_IN_FLIGHT: usize = 64;
+// A reply can queue behind a full window of chunks, so allow for slow links
+const TRANSFER_TIMEOUT_SECS: u64 = 120;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+// Running transfers by ID, for cancel_transfer
+static TRANSFERS: Lazy<parking_lot::Mutex<HashMap<String, watch::Sender<TransferSignal>>>> =
+    Lazy::new(|| parking_lot::Mutex::new(HashMap::new()));
+
+/// Receives progress reports of transfers
+pub type ProgressSink = mpsc::UnboundedSender<TransferProgress>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransferSignal {
+    Running,
+    Cancelled { remove_partial: bool },
 }
 
-pub struct SftpConnection {
-    session_id: String,
-    sftp: SftpSession,
-    _handle: client::Handle<SftpHandler>,
-    #[allow(dead_code)]
-    jump_connection_id: Option<String>,
+/// The error a transfer stopped by `cancel_transfer` ends with
+#[derive(Debug, thiserror::Error)]
+#[error("Transfer cancelled")]
+pub struct Cancelled;
+
+/// Progress reporting and cancellation for one download or upload, of a file
+/// or a whole directory. It can be cancelled by ID for as long as it is alive.
+pub struct Transfer {
+    id: String,
+    progress: Option<ProgressSink>,
+    signal: watch::Receiver<TransferSignal>,
+    started: Instant,
+    last_report: Option<Instant>,
+    total: Option<u64>,
+    done: u64,
+    // Bytes not moved by this transfer: kept from partial files, or left over
+    // by files that failed
+    skipped: u64,
+    files_done: usize,
+    files_total: usize,
 }
 
-// Trait for async read/write streams
-trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
-impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadWrite for T {}
+impl Transfer {
+    pub fn new(id: String, progress: Option<ProgressSink>) -> Self {
+        let (tx, signal) = watch::channel(TransferSignal::Running);
+        TRANSFERS.lock().insert(id.clone(), tx);
+        Self {
+            id,
+            progress,
+            signal,
+            started: Instant::now(),
+            last_report: None,
+            total: None,
+            done: 0,
+            skipped: 0,
+            files_done: 0,
+            files_total: 1,
+        }
+    }
 
-impl SftpConnection {
-    pub async fn connect(server: &ServerConfig) -> Result<Arc<Self>> {
-        let session_id = uuid::Uuid::new_v4().to_string();
+    fn skip(&mut self, bytes: u64) {
+        self.done += bytes;
+        self.skipped += bytes;
+    }
 
-        // Check if we need to use a jump host
-        let (stream, jump_connection_id): (Box<dyn AsyncReadWrite>, Option<String>) =
-            if let Some(jump_host_id) = &server.jump_host {
-                let (stream, conn_id) = Self::connect_via_jump_host(jump_host_id, server).await?;
-                (Box::new(stream), Some(conn_id))
-            } else {
-                let stream = Self::create_connection(server).await?;
-                (Box::new(stream), None)
-            };
+    /// Sizes up a directory transfer before its first file
+    fn start_tree(&mut self, entries: &[TreeEntry]) {
+        let files = entries.iter().filter(|entry| !entry.is_dir);
+        self.files_total = files.clone().count();
+        self.total = Some(files.map(|entry| entry.size).sum());
+        self.report();
+    }
+
+    /// Moves on to the next file of a directory transfer, where `done` bytes
+    /// should have been reached. What a failed file didn't get to is skipped.
+    fn next_file(&mut self, done: u64) {
+        self.skip(done.saturating_sub(self.done));
+        self.files_done += 1;
+        self.report();
+    }
 
-        // SSH config
-        let config = client::Config::default();
-        let config = Arc::new(config);
+    /// Counts `bytes` more as moved, reporting at most every PROGRESS_INTERVAL
+    fn advance(&mut self, bytes: u64) {
+        self.done += bytes;
+        if self
+            .last_report
+            .is_some_and(|at| at.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.report();
+    }
 
-        let handler = SftpHandler;
+    fn report(&mut self) {
+        self.last_report = Some(Instant::now());
+        let Some(progress) = &self.progress else {
+            return;
+        };
 
-        let handle = client::connect_stream(config, stream, handler)
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let bytes_per_sec = if elapsed > 0.0 {
+            ((self.done - self.skipped) as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let eta_secs = match self.total {
+            Some(total) if bytes_per_sec > 0 => {
+                Some(total.saturating_sub(self.done) / bytes_per_sec)
+            }
+            _ => None,
+        };
+        progress
+            .send(TransferProgress {
+                transfer_id: self.id.clone(),
+                bytes_done: self.done,
+                bytes_total: self.total,
+                bytes_per_sec,
+                eta_secs,
+                files_done: self.files_done,
+                files_total: self.files_total,
+            })
+            .ok();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        *self.signal.borrow() != TransferSignal::Running
+    }
+
+    /// Resolves once the transfer is cancelled
+    async fn cancelled(&self) {
+        let mut signal = self.signal.clone();
+        if signal
+            .wait_for(|signal| *signal != TransferSignal::Running)
             .await
-            .context("Failed to establish SSH connection")?;
+            .is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    fn remove_partial(&self) -> bool {
+        matches!(
+            *self.signal.borrow(),
+            TransferSignal::Cancelled {
+                remove_partial: true
+            }
+        )
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        TRANSFERS.lock().remove(&self.id);
+    }
+}
+
+/// Stops a running download or upload. With `remove_partial`, the file it
+/// was writing is deleted as well.
+pub fn cancel_transfer(transfer_id: &str, remove_partial: bool) -> Result<()> {
+    let transfers = TRANSFERS.lock();
+    let signal = transfers
+        .get(transfer_id)
+        .ok_or_else(|| anyhow!("Transfer not found"))?;
+    signal.send_replace(TransferSignal::Cancelled { remove_partial });
+    Ok(())
+}
+
+pub struct SftpConnection {
+    session_id: String,
+    server_id: String,
+    sftp: SftpSession,
+    // Opened on the first download or upload
+    transfers: OnceCell<Arc<RawSftpSession>>,
+    connection: Arc<PooledConnection>,
+}
+
+impl SftpConnection {
+    pub async fn connect(server: &ServerConfig, prompter: Option<Prompter>) -> Result<Arc<Self>> {
+        let session_id = uuid::Uuid::new_v4().to_string();
 
-        // Authenticate
-        let mut handle = handle;
-        Self::authenticate(&mut handle, server).await?;
+        let connection = pool::acquire(server, prompter).await?;
 
         // Open SFTP channel
-        let channel = handle
-            .channel_open_session()
+        let channel = connection
+            .open_session_channel()
             .await
             .context("Failed to open channel")?;
 
@@ -80,9 +221,10 @@ impl SftpConnection {
 
         let connection = Arc::new(Self {
             session_id: session_id.clone(),
+            server_id: server.id.clone(),
             sftp,
-            _handle: handle,
-            jump_connection_id,
+            transfers: OnceCell::new(),
+            connection,
         });
 
         SFTP_SESSIONS
@@ -93,193 +235,6 @@ impl SftpConnection {
         Ok(connection)
     }
 
-    async fn connect_via_jump_host(
-        jump_host_id: &str,
-        target_server: &ServerConfig,
-    ) -> Result<(ChannelStream<client::Msg>, String)> {
-        // Get jump host server config
-        let jump_server =
-            storage::get_server(jump_host_id).context("Jump host server not found")?;
-
-        // Connect to jump host
-        let jump_stream = Self::create_connection(&jump_server).await?;
-
-        let config = client::Config {
-            inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
-            ..Default::default()
-        };
-        let config = Arc::new(config);
-
-        let handler = JumpHostHandler;
-        let mut jump_handle = client::connect_stream(config, jump_stream, handler)
-            .await
-            .context("Failed to connect to jump host")?;
-
-        // Authenticate to jump host
-        Self::authenticate_jump_host(&mut jump_handle, &jump_server).await?;
-
-        // Open direct-tcpip channel to target server
-        let channel = jump_handle
-            .channel_open_direct_tcpip(
-                &target_server.host,
-                target_server.port as u32,
-                "127.0.0.1",
-                0,
-            )
-            .await
-            .context("Failed to open tunnel through jump host")?;
-
-        // Store jump connection to keep it alive
-        let conn_id = uuid::Uuid::new_v4().to_string();
-        let jump_conn = Arc::new(SftpJumpHostConnection {
-            handle: jump_handle,
-        });
-        SFTP_JUMP_CONNECTIONS
-            .write()
-            .await
-            .insert(conn_id.clone(), jump_conn);
-
-        Ok((channel.into_stream(), conn_id))
-    }
-
-    async fn authenticate_jump_host(
-        handle: &mut client::Handle<JumpHostHandler>,
-        server: &ServerConfig,
-    ) -> Result<()> {
-        match &server.auth {
-            AuthMethod::Password(password) => {
-                let auth_result = handle
-                    .authenticate_password(&server.username, password)
-                    .await
-                    .context("Jump host password authentication failed")?;
-
-                if !auth_result {
-                    anyhow::bail!("Jump host: Invalid username or password");
-                }
-            }
-            AuthMethod::PrivateKey { key, passphrase } => {
-                let passphrase_opt = passphrase.as_ref().filter(|p| !p.is_empty());
-                let key_pair = if let Some(passphrase) = passphrase_opt {
-                    decode_secret_key(key, Some(passphrase))
-                        .context("Failed to decode jump host private key with passphrase")?
-                } else {
-                    decode_secret_key(key, None).context("Failed to decode jump host private key")?
-                };
-
-                let auth_result = handle
-                    .authenticate_publickey(&server.username, Arc::new(key_pair))
-                    .await
-                    .context("Jump host public key authentication failed")?;
-
-                if !auth_result {
-                    anyhow::bail!("Jump host: Public key authentication rejected");
-                }
-            }
-        }
-        Ok(())
-    }
-
-    async fn create_connection(server: &ServerConfig) -> Result<TcpStream> {
-        let target_addr = format!("{}:{}", server.host, server.port);
-
-        match &server.proxy {
-            Some(proxy) => Self::connect_via_proxy(proxy, &target_addr).await,
-            None => TcpStream::connect(&target_addr)
-                .await
-                .context("Failed to connect to server"),
-        }
-    }
-
-    async fn connect_via_proxy(proxy: &ProxyConfig, target: &str) -> Result<TcpStream> {
-        let proxy_addr = format!("{}:{}", proxy.host, proxy.port);
-
-        match proxy.proxy_type {
-            ProxyType::Socks5 => {
-                let stream = if let (Some(user), Some(pass)) = (&proxy.username, &proxy.password) {
-                    Socks5Stream::connect_with_password(proxy_addr.as_str(), target, user, pass)
-                        .await
-                        .context("Failed to connect via SOCKS5 proxy")?
-                } else {
-                    Socks5Stream::connect(proxy_addr.as_str(), target)
-                        .await
-                        .context("Failed to connect via SOCKS5 proxy")?
-                };
-                Ok(stream.into_inner())
-            }
-            ProxyType::Http => {
-                let mut stream = TcpStream::connect(&proxy_addr)
-                    .await
-                    .context("Failed to connect to HTTP proxy")?;
-
-                let connect_request = if let (Some(user), Some(pass)) =
-                    (&proxy.username, &proxy.password)
-                {
-                    let credentials = base64::Engine::encode(
-                        &base64::engine::general_purpose::STANDARD,
-                        format!("{}:{}", user, pass),
-                    );
-                    format!(
-                        "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Basic {}\r\n\r\n",
-                        target, target, credentials
-                    )
-                } else {
-                    format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target)
-                };
-
-                stream.write_all(connect_request.as_bytes()).await?;
-
-                let mut response = vec![0u8; 1024];
-                let n = stream.read(&mut response).await?;
-                let response_str = String::from_utf8_lossy(&response[..n]);
-
-                if !response_str.contains("200") {
-                    anyhow::bail!("HTTP proxy connection failed: {}", response_str);
-                }
-
-                Ok(stream)
-            }
-        }
-    }
-
-    async fn authenticate(
-        handle: &mut client::Handle<SftpHandler>,
-        server: &ServerConfig,
-    ) -> Result<()> {
-        match &server.auth {
-            AuthMethod::Password(password) => {
-                let auth_result = handle
-                    .authenticate_password(&server.username, password)
-                    .await
-                    .context("Password authentication failed")?;
-
-                if !auth_result {
-                    anyhow::bail!("Invalid username or password");
-                }
-            }
-            AuthMethod::PrivateKey { key, passphrase } => {
-                // Treat empty passphrase as None
-                let passphrase_opt = passphrase.as_ref().filter(|p| !p.is_empty());
-                let key_pair = if let Some(passphrase) = passphrase_opt {
-                    decode_secret_key(key, Some(passphrase))
-                        .context("Failed to decode private key with passphrase")?
-                } else {
-                    decode_secret_key(key, None).context("Failed to decode private key")?
-                };
-
-                let auth_result = handle
-                    .authenticate_publickey(&server.username, Arc::new(key_pair))
-                    .await
-                    .context("Public key authentication failed")?;
-
-                if !auth_result {
-                    anyhow::bail!("Public key authentication rejected");
-                }
-            }
-        }
-
-        Ok(())
-    }
-
     pub async fn list_dir(&self, path: &str) -> Result<Vec<FileEntry>> {
         let dir = self.sftp.read_dir(path).await?;
         let mut entries = Vec::new();
@@ -292,42 +247,42 @@ impl SftpConnection {
             let permissions = format!(
                 "{}{}{}{}{}{}{}{}{}",
                 if is_dir { 'd' } else { '-' },
-                if metadata.permissions.map_or(false, |p| p & 0o400 != 0) {
+                if metadata.permissions.is_some_and(|p| p & 0o400 != 0) {
                     'r'
                 } else {
                     '-'
                 },
-                if metadata.permissions.map_or(false, |p| p & 0o200 != 0) {
+                if metadata.permissions.is_some_and(|p| p & 0o200 != 0) {
                     'w'
                 } else {
                     '-'
                 },
-                if metadata.permissions.map_or(false, |p| p & 0o100 != 0) {
+                if metadata.permissions.is_some_and(|p| p & 0o100 != 0) {
                     'x'
                 } else {
                     '-'
                 },
-                if metadata.permissions.map_or(false, |p| p & 0o040 != 0) {
+                if metadata.permissions.is_some_and(|p| p & 0o040 != 0) {
                     'r'
                 } else {
                     '-'
                 },
-                if metadata.permissions.map_or(false, |p| p & 0o020 != 0) {
+                if metadata.permissions.is_some_and(|p| p & 0o020 != 0) {
                     'w'
                 } else {
                     '-'
                 },
-                if metadata.permissions.map_or(false, |p| p & 0o010 != 0) {
+                if metadata.permissions.is_some_and(|p| p & 0o010 != 0) {
                     'x'
                 } else {
                     '-'
                 },
-                if metadata.permissions.map_or(false, |p| p & 0o004 != 0) {
+                if metadata.permissions.is_some_and(|p| p & 0o004 != 0) {
                     'r'
                 } else {
                     '-'
                 },
-                if metadata.permissions.map_or(false, |p| p & 0o002 != 0) {
+                if metadata.permissions.is_some_and(|p| p & 0o002 != 0) {
                     'w'
                 } else {
                     '-'
@@ -367,6 +322,471 @@ impl SftpConnection {
         Ok(())
     }
 
+    /// Copies a remote file to `local_path` without holding it in memory,
+    /// returning the number of bytes copied
+    pub async fn download(
+        &self,
+        remote_path: &str,
+        local_path: &Path,
+        resume: ResumeMode,
+        transfer: &mut Transfer,
+    ) -> Result<u64> {
+        let key = self.partial_key("download", remote_path, local_path);
+        let mut checkpoint = Checkpoint::new(key.clone(), 0);
+        let sftp = self.transfers().await?;
+        let handle = sftp
+            .open(remote_path, OpenFlags::READ, FileAttributes::empty())
+            .await?
+            .handle;
+
+        let result = async {
+            let attrs = sftp.fstat(handle.as_str()).await?.attrs;
+            // A directory transfer has already added up all its files
+            transfer.total = transfer.total.or(attrs.size);
+            let source = SourceStamp {
+                size: attrs.size.unwrap_or(0),
+                mtime: attrs.mtime.unwrap_or(0) as i64,
+            };
+
+            let partial = match resume {
+                ResumeMode::Restart => 0,
+                _ => fs::metadata(local_path).await.map(|m| m.len()).unwrap_or(0),
+            };
+            let offset = resume_offset(resume, storage::partial_transfer(&key), source, partial);
+            storage::record_partial_transfer(
+                &key,
+                PartialTransfer {
+                    source,
+                    written: offset,
+                },
+            )?;
+            checkpoint.written = offset;
+
+            let mut local = if offset > 0 {
+                let mut local = fs::OpenOptions::new()
+                    .write(true)
+                    .open(local_path)
+                    .await
+                    .context("Failed to open local file")?;
+                local.seek(SeekFrom::Start(offset)).await?;
+                local
+            } else {
+                fs::File::create(local_path)
+                    .await
+                    .context("Failed to create local file")?
+            };
+            transfer.skip(offset);
+            download_to(
+                &sftp,
+                &handle,
+                offset,
+                source.size,
+                &mut local,
+                transfer,
+                &mut checkpoint,
+            )
+            .await
+        }
+        .await;
+        sftp.close(handle).await.ok();
+
+        match result {
+            Ok(_) => {
+                storage::forget_partial_transfer(&key).ok();
+                transfer.report();
+            }
+            Err(_) if transfer.remove_partial() => {
+                fs::remove_file(local_path).await.ok();
+                storage::forget_partial_transfer(&key).ok();
+            }
+            Err(_) => checkpoint.save(),
+        }
+        result
+    }
+
+    /// Copies a local file to `remote_path` without holding it in memory,
+    /// returning the number of bytes copied
+    pub async fn upload(
+        &self,
+        local_path: &Path,
+        remote_path: &str,
+        resume: ResumeMode,
+        transfer: &mut Transfer,
+    ) -> Result<u64> {
+        let key = self.partial_key("upload", remote_path, local_path);
+        let mut local = fs::File::open(local_path)
+            .await
+            .context("Failed to open local file")?;
+        let metadata = local
+            .metadata()
+            .await
+            .context("Failed to read local file")?;
+        transfer.total = transfer.total.or(Some(metadata.len()));
+        let source = SourceStamp {
+            size: metadata.len(),
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs() as i64),
+        };
+
+        let sftp = self.transfers().await?;
+        let partial = match resume {
+            ResumeMode::Restart => 0,
+            _ => match sftp.stat(remote_path).await {
+                Ok(stat) => stat.attrs.size.unwrap_or(0),
+                Err(_) => 0,
+            },
+        };
+        let offset = resume_offset(resume, storage::partial_transfer(&key), source, partial);
+        storage::record_partial_transfer(
+            &key,
+            PartialTransfer {
+                source,
+                written: offset,
+            },
+        )?;
+        let mut checkpoint = Checkpoint::new(key.clone(), offset);
+
+        // Writes carry their offset, so resuming just skips truncating
+        let flags = if offset > 0 {
+            OpenFlags::WRITE
+        } else {
+            OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE
+        };
+        let handle = sftp
+            .open(remote_path, flags, FileAttributes::empty())
+            .await?
+            .handle;
+
+        let result = async {
+            local.seek(SeekFrom::Start(offset)).await?;
+            transfer.skip(offset);
+            upload_from(
+                &sftp,
+                &handle,
+                offset,
+                &mut local,
+                transfer,
+                &mut checkpoint,
+            )
+            .await
+        }
+        .await;
+        // Some servers only report a failed write when the file is closed
+        let closed = sftp.close(handle).await;
+
+        if result.is_err() && transfer.remove_partial() {
+            sftp.remove(remote_path).await.ok();
+            storage::forget_partial_transfer(&key).ok();
+        } else if result.is_err() {
+            checkpoint.save();
+        }
+        let written = result?;
+        closed?;
+        storage::forget_partial_transfer(&key).ok();
+        transfer.report();
+        Ok(written)
+    }
+
+    /// Copies a remote directory tree to `local_path`, keeping permissions and
+    /// mtimes. Files that fail are listed in the summary and skipped.
+    pub async fn download_dir(
+        &self,
+        remote_path: &str,
+        local_path: &Path,
+        resume: ResumeMode,
+        transfer: &mut Transfer,
+    ) -> Result<TransferSummary> {
+        let mut summary = TransferSummary::default();
+        let entries = self.walk_remote(remote_path, &mut summary.failures).await?;
+        transfer.start_tree(&entries);
+        fs::create_dir_all(local_path)
+            .await
+            .context("Failed to create local directory")?;
+
+        // The root comes first and was created above
+        for entry in &entries[1..] {
+            let done = transfer.done + entry.size;
+            let local = match local_join(local_path, &entry.path) {
+                Ok(local) => local,
+                Err(e) => {
+                    summary.failures.push(failure(&entry.path, e));
+                    transfer.next_file(done);
+                    continue;
+                }
+            };
+            if entry.is_dir {
+                if let Err(e) = fs::create_dir_all(&local).await {
+                    summary.failures.push(failure(&entry.path, e));
+                }
+                continue;
+            }
+
+            let remote = remote_join(remote_path, &entry.path);
+            let result = match self.download(&remote, &local, resume, transfer).await {
+                Ok(bytes) => {
+                    summary.bytes += bytes;
+                    set_local_attrs(&local, entry).await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => summary.files += 1,
+                Err(e) if e.is::<Cancelled>() => return Err(e),
+                Err(e) => summary.failures.push(failure(&entry.path, e)),
+            }
+            transfer.next_file(done);
+        }
+
+        // Writing into a directory changes its mtime, so they go last, and
+        // only as far as the platform allows
+        for entry in entries.iter().rev().filter(|entry| entry.is_dir) {
+            if let Ok(local) = local_join(local_path, &entry.path) {
+                set_local_attrs(&local, entry).await.ok();
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Copies a local directory tree to `remote_path`, keeping permissions
+    /// and mtimes. Files that fail are listed in the summary and skipped.
+    pub async fn upload_dir(
+        &self,
+        local_path: &Path,
+        remote_path: &str,
+        resume: ResumeMode,
+        transfer: &mut Transfer,
+    ) -> Result<TransferSummary> {
+        let mut summary = TransferSummary::default();
+        let entries = walk_local(local_path, &mut summary.failures).await?;
+        transfer.start_tree(&entries);
+        self.ensure_dir(remote_path).await?;
+
+        // The root comes first and was created above
+        for entry in &entries[1..] {
+            let remote = remote_join(remote_path, &entry.path);
+            if entry.is_dir {
+                if let Err(e) = self.ensure_dir(&remote).await {
+                    summary.failures.push(failure(&entry.path, e));
+                }
+                continue;
+            }
+
+            let done = transfer.done + entry.size;
+            let local = match local_join(local_path, &entry.path) {
+                Ok(local) => local,
+                Err(e) => {
+                    summary.failures.push(failure(&entry.path, e));
+                    transfer.next_file(done);
+                    continue;
+                }
+            };
+            let result = match self.upload(&local, &remote, resume, transfer).await {
+                Ok(bytes) => {
+                    summary.bytes += bytes;
+                    self.sftp
+                        .set_metadata(remote, entry.attributes())
+                        .await
+                        .context("Failed to set permissions and times")
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => summary.files += 1,
+                Err(e) if e.is::<Cancelled>() => return Err(e),
+                Err(e) => summary.failures.push(failure(&entry.path, e)),
+            }
+            transfer.next_file(done);
+        }
+
+        // Writing into a directory changes its mtime, so they go last
+        for entry in entries.iter().rev().filter(|entry| entry.is_dir) {
+            let remote = remote_join(remote_path, &entry.path);
+            if let Err(e) = self.sftp.set_metadata(remote, entry.attributes()).await {
+                summary.failures.push(failure(&entry.path, e));
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Downloads a file, or a directory with everything in it
+    pub async fn download_path(
+        &self,
+        remote_path: &str,
+        local_path: &Path,
+        resume: ResumeMode,
+        transfer: &mut Transfer,
+    ) -> Result<TransferSummary> {
+        if self.is_dir(remote_path).await.unwrap_or(false) {
+            return self
+                .download_dir(remote_path, local_path, resume, transfer)
+                .await;
+        }
+        let bytes = self
+            .download(remote_path, local_path, resume, transfer)
+            .await?;
+        Ok(TransferSummary {
+            files: 1,
+            bytes,
+            ..Default::default()
+        })
+    }
+
+    /// Uploads a file, or a directory with everything in it
+    pub async fn upload_path(
+        &self,
+        local_path: &Path,
+        remote_path: &str,
+        resume: ResumeMode,
+        transfer: &mut Transfer,
+    ) -> Result<TransferSummary> {
+        if local_path.is_dir() {
+            return self
+                .upload_dir(local_path, remote_path, resume, transfer)
+                .await;
+        }
+        let bytes = self
+            .upload(local_path, remote_path, resume, transfer)
+            .await?;
+        Ok(TransferSummary {
+            files: 1,
+            bytes,
+            ..Default::default()
+        })
+    }
+
+    pub async fn is_dir(&self, path: &str) -> Result<bool> {
+        Ok(self.sftp.metadata(path).await?.file_type().is_dir())
+    }
+
+    /// Creates a remote directory unless it already exists
+    async fn ensure_dir(&self, path: &str) -> Result<()> {
+        if let Err(e) = self.sftp.create_dir(path).await {
+            if !self.is_dir(path).await.unwrap_or(false) {
+                return Err(e).with_context(|| format!("Failed to create {}", path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists a remote directory tree, the root first and parents before their
+    /// children. What can't be copied, like symbolic links and unreadable
+    /// subdirectories, is added to `failures`.
+    async fn walk_remote(
+        &self,
+        root: &str,
+        failures: &mut Vec<TransferFailure>,
+    ) -> Result<Vec<TreeEntry>> {
+        let metadata = self.sftp.metadata(root).await?;
+        if !metadata.file_type().is_dir() {
+            return Err(anyhow!("Not a directory: {}", root));
+        }
+
+        let mut entries = vec![TreeEntry::remote(String::new(), &metadata)];
+        let mut dirs = vec![String::new()];
+        while let Some(dir) = dirs.pop() {
+            let listing = match self.sftp.read_dir(remote_join(root, &dir)).await {
+                Ok(listing) => listing,
+                Err(e) if dir.is_empty() => return Err(e.into()),
+                Err(e) => {
+                    failures.push(failure(&dir, e));
+                    continue;
+                }
+            };
+            for entry in listing {
+                let name = entry.file_name();
+                let path = child_path(&dir, &name);
+                // The server names files, so it mustn't get to pick where
+                // they are written locally
+                if !is_plain_name(&name) {
+                    failures.push(failure(&path, anyhow!("Skipped, not a valid file name")));
+                    continue;
+                }
+                let file_type = entry.file_type();
+                if file_type.is_dir() {
+                    dirs.push(path.clone());
+                } else if !file_type.is_file() {
+                    failures.push(failure(&path, anyhow!(NOT_COPIED)));
+                    continue;
+                }
+                entries.push(TreeEntry::remote(path, &entry.metadata()));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Names a transfer between two paths, to find its partial file's record
+    fn partial_key(&self, direction: &str, remote_path: &str, local_path: &Path) -> String {
+        format!(
+            "{} {}:{} {}",
+            direction,
+            self.server_id,
+            remote_path,
+            local_path.display()
+        )
+    }
+
+    /// The SFTP channel downloads and uploads run on. It is separate from the
+    /// one for browsing, so a big transfer doesn't hold up directory listings.
+    async fn transfers(&self) -> Result<Arc<RawSftpSession>> {
+        self.transfers
+            .get_or_try_init(|| async {
+                let channel = self
+                    .connection
+                    .open_session_channel()
+                    .await
+                    .context("Failed to open channel")?;
+                channel
+                    .request_subsystem(false, "sftp")
+                    .await
+                    .context("Failed to request SFTP subsystem")?;
+
+                let sftp = RawSftpSession::new(channel.into_stream());
+                sftp.init().await?;
+                sftp.set_timeout(TRANSFER_TIMEOUT_SECS).await;
+                anyhow::Ok(Arc::new(sftp))
+            })
+            .await
+            .cloned()
+    }
+
+    /// Deletes a remote directory and everything in it. Symbolic links are
+    /// deleted, not followed.
+    pub async fn remove_dir_all(&self, path: &str) -> Result<()> {
+        let mut dirs = vec![path.to_string()];
+        let mut emptied = Vec::new();
+        while let Some(dir) = dirs.pop() {
+            let listing = self
+                .sftp
+                .read_dir(dir.as_str())
+                .await
+                .with_context(|| format!("Failed to read {}", dir))?;
+            for entry in listing {
+                let child = format!("{}/{}", dir.trim_end_matches('/'), entry.file_name());
+                if entry.file_type().is_dir() {
+                    dirs.push(child);
+                } else {
+                    self.sftp
+                        .remove_file(child.as_str())
+                        .await
+                        .with_context(|| format!("Failed to delete {}", child))?;
+                }
+            }
+            emptied.push(dir);
+        }
+
+        // Children were listed after their parents, so remove them first
+        for dir in emptied.iter().rev() {
+            self.sftp
+                .remove_dir(dir.as_str())
+                .await
+                .with_context(|| format!("Failed to delete {}", dir))?;
+        }
+        Ok(())
+    }
+
     pub async fn delete(&self, path: &str, is_dir: bool) -> Result<()> {
         if is_dir {
             self.sftp.remove_dir(path).await?;
@@ -390,17 +810,434 @@ impl SftpConnection {
         &self.session_id
     }
 
+    pub async fn is_closed(&self) -> bool {
+        self.connection.is_closed().await
+    }
+
     pub async fn close(&self) -> Result<()> {
         self.sftp.close().await?;
         SFTP_SESSIONS.write().await.remove(&self.session_id);
 
-        // Clean up jump connection if exists
-        if let Some(conn_id) = &self.jump_connection_id {
-            SFTP_JUMP_CONNECTIONS.write().await.remove(conn_id);
+        Ok(())
+    }
+}
+
+// Why a directory transfer passes over symbolic links, devices and the like
+const NOT_COPIED: &str = "Skipped, not a regular file or directory";
+
+/// A file or directory of a tree being transferred
+struct TreeEntry {
+    // Relative to the tree's root, with `/` separators; empty for the root
+    path: String,
+    is_dir: bool,
+    size: u64,
+    permissions: Option<u32>,
+    atime: Option<u32>,
+    mtime: Option<u32>,
+}
+
+impl TreeEntry {
+    fn remote(path: String, attrs: &FileAttributes) -> Self {
+        Self {
+            path,
+            is_dir: attrs.file_type().is_dir(),
+            size: attrs.size.unwrap_or(0),
+            permissions: attrs.permissions.map(|mode| mode & 0o7777),
+            atime: attrs.atime,
+            mtime: attrs.mtime,
         }
+    }
 
-        Ok(())
+    fn local(path: String, metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let permissions = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let permissions = None;
+
+        Self {
+            path,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            permissions,
+            atime: unix_secs(metadata.accessed()),
+            mtime: unix_secs(metadata.modified()),
+        }
+    }
+
+    /// What to set on the uploaded copy. SFTP sets both times or neither.
+    fn attributes(&self) -> FileAttributes {
+        FileAttributes {
+            permissions: self.permissions,
+            atime: self.mtime.map(|mtime| self.atime.unwrap_or(mtime)),
+            mtime: self.mtime,
+            ..FileAttributes::empty()
+        }
+    }
+}
+
+fn unix_secs(time: std::io::Result<SystemTime>) -> Option<u32> {
+    let since = time.ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(since.as_secs() as u32)
+}
+
+fn failure(path: &str, error: impl Into<anyhow::Error>) -> TransferFailure {
+    TransferFailure {
+        path: path.to_string(),
+        error: format!("{:#}", error.into()),
+    }
+}
+
+fn child_path(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+fn remote_join(root: &str, path: &str) -> String {
+    if path.is_empty() {
+        root.to_string()
+    } else {
+        format!("{}/{}", root.trim_end_matches('/'), path)
+    }
+}
+
+/// Joins a tree entry's path onto a local directory, refusing any that would
+/// land outside it
+fn local_join(root: &Path, path: &str) -> Result<PathBuf> {
+    let joined = path
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .fold(root.to_path_buf(), |joined, part| joined.join(part));
+    let inside = joined.strip_prefix(root).is_ok_and(|rest| {
+        rest.components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    });
+    if !inside {
+        return Err(anyhow!("Refusing to write outside {}", root.display()));
+    }
+    Ok(joined)
+}
+
+/// Whether a name from a directory listing names a file in that directory,
+/// not a path that leads elsewhere
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains(['/', '\\', '\0'])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
+
+/// Lists a local directory tree, the root first and parents before their
+/// children. What can't be copied, like symbolic links and unreadable
+/// subdirectories, is added to `failures`.
+async fn walk_local(root: &Path, failures: &mut Vec<TransferFailure>) -> Result<Vec<TreeEntry>> {
+    let metadata = fs::metadata(root)
+        .await
+        .context("Failed to read local directory")?;
+    if !metadata.is_dir() {
+        return Err(anyhow!("Not a directory: {}", root.display()));
+    }
+
+    let mut entries = vec![TreeEntry::local(String::new(), &metadata)];
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        let listing = match local_join(root, &dir) {
+            Ok(local) => fs::read_dir(local).await.map_err(anyhow::Error::from),
+            Err(e) => Err(e),
+        };
+        let mut listing = match listing {
+            Ok(listing) => listing,
+            Err(e) if dir.is_empty() => return Err(e).context("Failed to read local directory"),
+            Err(e) => {
+                failures.push(failure(&dir, e));
+                continue;
+            }
+        };
+        loop {
+            let entry = match listing.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    failures.push(failure(&dir, e));
+                    break;
+                }
+            };
+            let path = child_path(&dir, &entry.file_name().to_string_lossy());
+            // Doesn't follow symbolic links
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    failures.push(failure(&path, e));
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                dirs.push(path.clone());
+            } else if !metadata.is_file() {
+                failures.push(failure(&path, anyhow!(NOT_COPIED)));
+                continue;
+            }
+            entries.push(TreeEntry::local(path, &metadata));
+        }
+    }
+    Ok(entries)
+}
+
+/// Gives a downloaded file or directory the permissions and times it had on
+/// the server
+async fn set_local_attrs(path: &Path, entry: &TreeEntry) -> Result<()> {
+    // Times first, as the permissions may not let us open it afterwards
+    if let Some(mtime) = entry.mtime {
+        let at = |secs: u32| UNIX_EPOCH + Duration::from_secs(secs as u64);
+        let times = std::fs::FileTimes::new()
+            .set_accessed(at(entry.atime.unwrap_or(mtime)))
+            .set_modified(at(mtime));
+        // Directories can't be opened for writing, files need it on Windows
+        let file = fs::OpenOptions::new()
+            .read(entry.is_dir)
+            .write(!entry.is_dir)
+            .open(path)
+            .await
+            .context("Failed to set times")?;
+        file.into_std()
+            .await
+            .set_times(times)
+            .context("Failed to set times")?;
+    }
+
+    #[cfg(unix)]
+    if let Some(mode) = entry.permissions {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .await
+            .context("Failed to set permissions")?;
+    }
+    Ok(())
+}
+
+/// How much of a partial destination file of `partial` bytes a transfer can
+/// keep, given what was `recorded` when it was last written to
+fn resume_offset(
+    resume: ResumeMode,
+    recorded: Option<PartialTransfer>,
+    source: SourceStamp,
+    partial: u64,
+) -> u64 {
+    // A longer destination can't be a prefix of the source
+    if partial > source.size {
+        return 0;
+    }
+    // Past the gapless prefix there may be holes
+    let kept = recorded.map_or(partial, |recorded| partial.min(recorded.written));
+    match resume {
+        ResumeMode::Restart => 0,
+        ResumeMode::Append => kept,
+        ResumeMode::Verify if recorded.is_some_and(|recorded| recorded.source == source) => kept,
+        ResumeMode::Verify => 0,
+    }
+}
+
+/// Tracks how far from the start a partial file is written without gaps, to
+/// save with its partial transfer record when the transfer stops short. Until
+/// then the record keeps the offset the transfer started from, which is safe
+/// to resume from if the app doesn't get to save.
+struct Checkpoint {
+    key: String,
+    written: u64,
+    // Chunks written past a gap, by offset
+    ahead: BTreeMap<u64, u64>,
+}
+
+impl Checkpoint {
+    fn new(key: String, written: u64) -> Self {
+        Self {
+            key,
+            written,
+            ahead: BTreeMap::new(),
+        }
+    }
+
+    /// Notes that `len` bytes at `offset` were written
+    fn add(&mut self, offset: u64, len: u64) {
+        self.ahead.insert(offset, len);
+        while let Some(len) = self.ahead.remove(&self.written) {
+            self.written += len;
+        }
+    }
+
+    fn save(&self) {
+        storage::update_partial_transfer(&self.key, self.written).ok();
+    }
+}
+
+/// Reads an open remote file of `size` bytes into `local` with a window of
+/// reads in flight. Replies can arrive in any order, so finished chunks wait
+/// in `ready` until everything before them has been written.
+async fn download_to(
+    sftp: &Arc<RawSftpSession>,
+    handle: &str,
+    offset: u64,
+    size: u64,
+    local: &mut fs::File,
+    transfer: &mut Transfer,
+    checkpoint: &mut Checkpoint,
+) -> Result<u64> {
+    let mut reads = JoinSet::new();
+    let mut ready: BTreeMap<u64, Option<Vec<u8>>> = BTreeMap::new();
+    let mut requested = offset;
+    let mut written = offset;
+    let mut eof = false;
+
+    loop {
+        if transfer.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        // Read ahead up to the size the file had when opened, then one chunk
+        // at a time until EOF in case it has grown (or reports no size)
+        let window = if requested < size { MAX_IN_FLIGHT } else { 1 };
+        while !eof && reads.len() + ready.len() < window {
+            reads.spawn(read_chunk(
+                sftp.clone(),
+                handle.to_string(),
+                requested,
+                CHUNK_SIZE,
+            ));
+            requested += CHUNK_SIZE as u64;
+        }
+
+        if let Some(chunk) = ready.remove(&written) {
+            let Some(data) = chunk else {
+                break;
+            };
+            local
+                .write_all(&data)
+                .await
+                .context("Failed to write local file")?;
+            checkpoint.add(written, data.len() as u64);
+            written += data.len() as u64;
+            transfer.advance(data.len() as u64);
+            continue;
+        }
+
+        let read = tokio::select! {
+            read = reads.join_next() => read,
+            _ = transfer.cancelled() => return Err(Cancelled.into()),
+        };
+        let Some(read) = read else {
+            break;
+        };
+        let (offset, len, data) = read??;
+        match data {
+            Some(data) if !data.is_empty() => {
+                // Servers may send less than asked for; fetch the rest separately
+                let got = data.len() as u32;
+                if got < len {
+                    reads.spawn(read_chunk(
+                        sftp.clone(),
+                        handle.to_string(),
+                        offset + got as u64,
+                        len - got,
+                    ));
+                }
+                ready.insert(offset, Some(data));
+            }
+            _ => {
+                eof = true;
+                ready.insert(offset, None);
+            }
+        }
+    }
+
+    local.flush().await.context("Failed to write local file")?;
+    Ok(written - offset)
+}
+
+/// Reads `len` bytes at `offset`, or None past the end of the file
+async fn read_chunk(
+    sftp: Arc<RawSftpSession>,
+    handle: String,
+    offset: u64,
+    len: u32,
+) -> Result<(u64, u32, Option<Vec<u8>>)> {
+    match sftp.read(handle, offset, len).await {
+        Ok(data) => Ok((offset, len, Some(data.data))),
+        Err(SftpError::Status(status)) if status.status_code == StatusCode::Eof => {
+            Ok((offset, len, None))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the rest of `local` to an open remote file from `start`, with a
+/// window of writes in flight. Writes can finish in any order, so only those
+/// with everything before them done move `checkpoint` on.
+async fn upload_from(
+    sftp: &Arc<RawSftpSession>,
+    handle: &str,
+    start: u64,
+    local: &mut fs::File,
+    transfer: &mut Transfer,
+    checkpoint: &mut Checkpoint,
+) -> Result<u64> {
+    let mut writes = JoinSet::new();
+    let mut offset = start;
+
+    loop {
+        if transfer.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+        (&mut *local)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)
+            .await
+            .context("Failed to read local file")?;
+        if chunk.is_empty() {
+            break;
+        }
+
+        while writes.len() >= MAX_IN_FLIGHT {
+            let written = tokio::select! {
+                write = writes.join_next() => write,
+                _ = transfer.cancelled() => return Err(Cancelled.into()),
+            };
+            if let Some(written) = written {
+                let (at, len) = written??;
+                checkpoint.add(at, len);
+                transfer.advance(len);
+            }
+        }
+        let sftp = sftp.clone();
+        let handle = handle.to_string();
+        let chunk_offset = offset;
+        let len = chunk.len() as u64;
+        offset += len;
+        writes.spawn(async move {
+            sftp.write(handle, chunk_offset, chunk).await?;
+            anyhow::Ok((chunk_offset, len))
+        });
+    }
+
+    loop {
+        let written = tokio::select! {
+            write = writes.join_next() => write,
+            _ = transfer.cancelled() => return Err(Cancelled.into()),
+        };
+        let Some(written) = written else {
+            break;
+        };
+        let (at, len) = written??;
+        checkpoint.add(at, len);
+        transfer.advance(len);
     }
+    Ok(offset - start)
 }
 
 pub async fn get_sftp_session(session_id: &str) -> Option<Arc<SftpConnection>> {
@@ -408,38 +1245,220 @@ pub async fn get_sftp_session(session_id: &str) -> Option<Arc<SftpConnection>> {
 }
 
 pub async fn remove_sftp_session(session_id: &str) {
-    if let Some(session) = SFTP_SESSIONS.write().await.remove(session_id) {
-        // Clean up jump connection if exists
-        if let Some(conn_id) = &session.jump_connection_id {
-            SFTP_JUMP_CONNECTIONS.write().await.remove(conn_id);
+    SFTP_SESSIONS.write().await.remove(session_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use russh_sftp::protocol::{Data, Status, Version};
+
+    const SOURCE: SourceStamp = SourceStamp {
+        size: 1000,
+        mtime: 1_700_000_000,
+    };
+
+    fn recorded(source: SourceStamp, written: u64) -> Option<PartialTransfer> {
+        Some(PartialTransfer { source, written })
+    }
+
+    #[test]
+    fn checkpoint_waits_for_gaps_to_fill() {
+        let mut checkpoint = Checkpoint::new("key".to_string(), 100);
+        checkpoint.add(300, 100);
+        checkpoint.add(200, 100);
+        assert_eq!(checkpoint.written, 100);
+        checkpoint.add(100, 100);
+        assert_eq!(checkpoint.written, 400);
+        assert!(checkpoint.ahead.is_empty());
+        checkpoint.add(500, 50);
+        assert_eq!(checkpoint.written, 400);
+    }
+
+    #[test]
+    fn resume_keeps_only_the_gapless_prefix() {
+        let offset = resume_offset(ResumeMode::Verify, recorded(SOURCE, 300), SOURCE, 600);
+        assert_eq!(offset, 300);
+        // A destination cut short after the record was saved
+        let offset = resume_offset(ResumeMode::Verify, recorded(SOURCE, 300), SOURCE, 200);
+        assert_eq!(offset, 200);
+    }
+
+    #[test]
+    fn resume_verify_restarts_when_the_source_changed() {
+        let changed = SourceStamp {
+            mtime: SOURCE.mtime + 1,
+            ..SOURCE
+        };
+        let offset = resume_offset(ResumeMode::Verify, recorded(changed, 300), SOURCE, 300);
+        assert_eq!(offset, 0);
+        assert_eq!(resume_offset(ResumeMode::Verify, None, SOURCE, 300), 0);
+    }
+
+    #[test]
+    fn resume_append_trusts_unrecorded_files() {
+        assert_eq!(resume_offset(ResumeMode::Append, None, SOURCE, 300), 300);
+        let changed = SourceStamp {
+            size: 2000,
+            ..SOURCE
+        };
+        let offset = resume_offset(ResumeMode::Append, recorded(changed, 100), SOURCE, 300);
+        assert_eq!(offset, 100);
+    }
+
+    #[test]
+    fn resume_restarts_past_the_source_or_when_asked() {
+        let offset = resume_offset(ResumeMode::Append, recorded(SOURCE, 1000), SOURCE, 1001);
+        assert_eq!(offset, 0);
+        let offset = resume_offset(ResumeMode::Restart, recorded(SOURCE, 300), SOURCE, 300);
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn listed_names_must_stay_in_their_directory() {
+        assert!(is_plain_name("notes.txt"));
+        assert!(is_plain_name(".hidden"));
+        assert!(is_plain_name("..."));
+        for name in ["", ".", "..", "a/b", "/etc", "..\\up", "a\0b"] {
+            assert!(!is_plain_name(name), "{:?}", name);
         }
     }
-}
 
-struct SftpHandler;
+    #[test]
+    fn local_join_stays_under_root() {
+        let root = Path::new("downloads");
+        assert_eq!(local_join(root, "").unwrap(), root);
+        assert_eq!(
+            local_join(root, "a/b.txt").unwrap(),
+            root.join("a").join("b.txt")
+        );
+        assert!(local_join(root, "a/../../b").is_err());
+        assert!(local_join(root, "..").is_err());
+        // Leading separators don't make it absolute
+        assert_eq!(
+            local_join(root, "/etc/passwd").unwrap(),
+            root.join("etc").join("passwd")
+        );
+    }
 
-#[async_trait]
-impl client::Handler for SftpHandler {
-    type Error = anyhow::Error;
+    // Replies never carry more than this, so every chunk comes back short
+    const SHORT_READ: usize = 20_000;
+
+    /// Serves reads of `file` over SFTP, sending each reply after a delay
+    /// that varies by offset so later chunks often overtake earlier ones
+    async fn serve_reads(stream: tokio::io::DuplexStream, file: Vec<u8>) {
+        let (mut requests, replies) = tokio::io::split(stream);
+        let replies = Arc::new(tokio::sync::Mutex::new(replies));
+        while let Ok(len) = requests.read_u32().await {
+            let mut packet = vec![0u8; len as usize];
+            if requests.read_exact(&mut packet).await.is_err() {
+                return;
+            }
+            let (kind, mut body) = (packet[0], &packet[1..]);
+            let reply = match kind {
+                // SSH_FXP_INIT, answered with SSH_FXP_VERSION
+                1 => (
+                    2,
+                    russh_sftp::ser::to_bytes(&Version::new()).unwrap().to_vec(),
+                ),
+                // SSH_FXP_READ
+                5 => {
+                    let id = body.read_u32().await.unwrap();
+                    let handle_len = body.read_u32().await.unwrap() as usize;
+                    body = &body[handle_len..];
+                    let offset = body.read_u64().await.unwrap() as usize;
+                    let len = body.read_u32().await.unwrap() as usize;
+
+                    let replies = replies.clone();
+                    let end = file.len().min(offset + len.min(SHORT_READ));
+                    let reply = if offset < file.len() {
+                        let data = file[offset..end].to_vec();
+                        (
+                            103,
+                            russh_sftp::ser::to_bytes(&Data { id, data })
+                                .unwrap()
+                                .to_vec(),
+                        )
+                    } else {
+                        let eof = Status {
+                            id,
+                            status_code: StatusCode::Eof,
+                            error_message: String::new(),
+                            language_tag: String::new(),
+                        };
+                        (101, russh_sftp::ser::to_bytes(&eof).unwrap().to_vec())
+                    };
+                    let delay = (offset / CHUNK_SIZE as usize * 7 % 5) as u64;
+                    tokio::spawn(async move {
+                        tokio::time::sleep(Duration::from_millis(delay)).await;
+                        send(&replies, reply).await;
+                    });
+                    continue;
+                }
+                _ => panic!("unexpected SFTP request {}", kind),
+            };
+            send(&replies, reply).await;
+        }
+    }
 
-    async fn check_server_key(
-        &mut self,
-        _server_public_key: &key::PublicKey,
-    ) -> Result<bool, Self::Error> {
-        Ok(true)
+    async fn send(
+        replies: &tokio::sync::Mutex<tokio::io::WriteHalf<tokio::io::DuplexStream>>,
+        (kind, payload): (u8, Vec<u8>),
+    ) {
+        let mut replies = replies.lock().await;
+        replies.write_u32(payload.len() as u32 + 1).await.ok();
+        replies.write_u8(kind).await.ok();
+        replies.write_all(&payload).await.ok();
     }
-}
 
-struct JumpHostHandler;
+    /// Downloads `file` from a fake server into a temporary file, telling
+    /// `download_to` it has `size` bytes, and returns what was written
+    async fn download(file: &[u8], size: u64) -> Vec<u8> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve_reads(server, file.to_vec()));
+        let sftp = Arc::new(RawSftpSession::new(client));
+        sftp.init().await.unwrap();
+
+        let path = std::env::temp_dir().join(format!("download-{}", uuid::Uuid::new_v4()));
+        let mut local = fs::File::create(&path).await.unwrap();
+        let mut transfer = Transfer::new(uuid::Uuid::new_v4().to_string(), None);
+        let mut checkpoint = Checkpoint::new(String::new(), 0);
+
+        let written = download_to(
+            &sftp,
+            "handle",
+            0,
+            size,
+            &mut local,
+            &mut transfer,
+            &mut checkpoint,
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, file.len() as u64);
+        assert_eq!(checkpoint.written, written);
+
+        let downloaded = fs::read(&path).await.unwrap();
+        fs::remove_file(&path).await.ok();
+        downloaded
+    }
 
-#[async_trait]
-impl client::Handler for JumpHostHandler {
-    type Error = anyhow::Error;
+    fn sample_file() -> Vec<u8> {
+        (0..5 * CHUNK_SIZE as usize + 1234)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn download_reassembles_late_and_short_replies() {
+        let file = sample_file();
+        assert_eq!(download(&file, file.len() as u64).await, file);
+    }
 
-    async fn check_server_key(
-        &mut self,
-        _server_public_key: &key::PublicKey,
-    ) -> Result<bool, Self::Error> {
-        Ok(true)
+    #[tokio::test]
+    async fn download_reads_past_a_stale_size() {
+        // The file grew after it was opened
+        let file = sample_file();
+        assert_eq!(download(&file, CHUNK_SIZE as u64).await, file);
     }
 }
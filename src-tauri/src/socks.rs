@@ -0,0 +1,219 @@
+use anyhow::{bail, Context, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+const NO_AUTH: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const IPV4: u8 = 0x01;
+const DOMAIN: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+// Clients that go quiet before naming a target are dropped after this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    ConnectionRefused = 0x05,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+/// Negotiates with a SOCKS5 client (RFC 1928, CONNECT only, with RFC 1929
+/// username/password auth when `credentials` is set) and returns the host and
+/// port it wants to reach. The caller answers with `reply` once the tunnel is
+/// open or failed.
+pub async fn accept<S>(stream: &mut S, credentials: Option<(&str, &str)>) -> Result<(String, u16)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, negotiate(stream, credentials))
+        .await
+        .context("SOCKS client took too long to negotiate")?
+}
+
+async fn negotiate<S>(stream: &mut S, credentials: Option<(&str, &str)>) -> Result<(String, u16)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let [version, method_count] = read_array(stream).await?;
+    if version != VERSION {
+        bail!("Unsupported SOCKS version {}", version);
+    }
+    let mut methods = vec![0u8; method_count as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if credentials.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTH
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        bail!("SOCKS client offered no acceptable authentication method");
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    if let Some((username, password)) = credentials {
+        let [_auth_version, username_len] = read_array(stream).await?;
+        let given_username = read_string(stream, username_len as usize).await?;
+        let [password_len] = read_array(stream).await?;
+        let given_password = read_string(stream, password_len as usize).await?;
+
+        let accepted = given_username == username && given_password == password;
+        stream
+            .write_all(&[AUTH_VERSION, if accepted { 0x00 } else { 0x01 }])
+            .await?;
+        if !accepted {
+            bail!("SOCKS client failed to authenticate");
+        }
+    }
+
+    let [version, command, _reserved, address_type] = read_array(stream).await?;
+    if version != VERSION {
+        bail!("Unsupported SOCKS version {}", version);
+    }
+    if command != CONNECT {
+        reply(stream, Reply::CommandNotSupported).await?;
+        bail!("Unsupported SOCKS command {}", command);
+    }
+
+    let host = match address_type {
+        IPV4 => Ipv4Addr::from(read_array::<_, 4>(stream).await?).to_string(),
+        IPV6 => Ipv6Addr::from(read_array::<_, 16>(stream).await?).to_string(),
+        DOMAIN => {
+            let [len] = read_array(stream).await?;
+            read_string(stream, len as usize).await?
+        }
+        _ => {
+            reply(stream, Reply::AddressTypeNotSupported).await?;
+            bail!("Unsupported SOCKS address type {}", address_type);
+        }
+    };
+    let port = u16::from_be_bytes(read_array(stream).await?);
+
+    Ok((host, port))
+}
+
+/// Answers a CONNECT request. The bound address is not meaningful for a
+/// tunnel, so it is always reported as 0.0.0.0:0.
+pub async fn reply<S>(stream: &mut S, reply: Reply) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&[VERSION, reply as u8, 0x00, IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+async fn read_array<S, const N: usize>(stream: &mut S) -> Result<[u8; N]>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = [0u8; N];
+    stream.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+async fn read_string<S>(stream: &mut S, len: usize) -> Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0u8; len];
+    stream.read_exact(&mut buffer).await?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `accept` against a client that sends `request` up front, and
+    /// returns what it got back along with the result
+    async fn negotiate_with(
+        request: &[u8],
+        credentials: Option<(&str, &str)>,
+    ) -> (Result<(String, u16)>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+        let result = accept(&mut server, credentials).await;
+        drop(server);
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).await.unwrap();
+        (result, answer)
+    }
+
+    #[tokio::test]
+    async fn connects_to_a_domain_without_auth() {
+        let mut request = vec![VERSION, 1, NO_AUTH, VERSION, CONNECT, 0, DOMAIN, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&443u16.to_be_bytes());
+
+        let (result, answer) = negotiate_with(&request, None).await;
+        assert_eq!(result.unwrap(), ("example.com".to_string(), 443));
+        assert_eq!(answer, [VERSION, NO_AUTH]);
+    }
+
+    #[tokio::test]
+    async fn connects_to_addresses_with_a_password() {
+        let mut request = vec![VERSION, 2, NO_AUTH, USERNAME_PASSWORD];
+        request.extend_from_slice(&[AUTH_VERSION, 4]);
+        request.extend_from_slice(b"user");
+        request.push(6);
+        request.extend_from_slice(b"secret");
+        request.extend_from_slice(&[VERSION, CONNECT, 0, IPV4, 10, 0, 0, 1, 0x1f, 0x90]);
+
+        let (result, answer) = negotiate_with(&request, Some(("user", "secret"))).await;
+        assert_eq!(result.unwrap(), ("10.0.0.1".to_string(), 8080));
+        assert_eq!(answer, [VERSION, USERNAME_PASSWORD, AUTH_VERSION, 0x00]);
+
+        let mut request = vec![VERSION, 1, NO_AUTH, VERSION, CONNECT, 0, IPV6];
+        request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        request.extend_from_slice(&22u16.to_be_bytes());
+        let (result, _) = negotiate_with(&request, None).await;
+        assert_eq!(result.unwrap(), ("::1".to_string(), 22));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_password() {
+        let mut request = vec![VERSION, 1, USERNAME_PASSWORD, AUTH_VERSION, 4];
+        request.extend_from_slice(b"user");
+        request.push(5);
+        request.extend_from_slice(b"wrong");
+
+        let (result, answer) = negotiate_with(&request, Some(("user", "secret"))).await;
+        assert!(result.is_err());
+        assert_eq!(answer, [VERSION, USERNAME_PASSWORD, AUTH_VERSION, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn requires_auth_when_configured() {
+        let request = [VERSION, 1, NO_AUTH];
+        let (result, answer) = negotiate_with(&request, Some(("user", "secret"))).await;
+        assert!(result.is_err());
+        assert_eq!(answer, [VERSION, NO_ACCEPTABLE_METHOD]);
+    }
+
+    #[tokio::test]
+    async fn refuses_other_commands_and_versions() {
+        // BIND
+        let request = [VERSION, 1, NO_AUTH, VERSION, 0x02, 0, IPV4];
+        let (result, answer) = negotiate_with(&request, None).await;
+        assert!(result.is_err());
+        assert_eq!(answer[..2], [VERSION, NO_AUTH]);
+        assert_eq!(answer[2..4], [VERSION, Reply::CommandNotSupported as u8]);
+
+        let (result, answer) = negotiate_with(&[0x04, 1, 0], None).await;
+        assert!(result.is_err());
+        assert!(answer.is_empty());
+    }
+}
@@ -1,83 +1,110 @@
-use crate::models::{AuthMethod, ProxyConfig, ProxyType, ServerConfig, TerminalSize};
-use crate::storage;
-use anyhow::{Context, Result};
-use async_trait::async_trait;
+use crate::models::{
+    BroadcastFailure, BroadcastMember, ServerConfig, SessionState, SessionStateChange, ShellExit,
+    TerminalSize,
+};
+use crate::pool::{self, PooledConnection};
+use crate::transport::Prompter;
+use crate::x11;
+use anyhow::{anyhow, Context, Result};
 use once_cell::sync::Lazy;
-use russh::keys::*;
 use russh::*;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex, RwLock};
-use tokio_socks::tcp::Socks5Stream;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+use tokio::task::JoinSet;
 
 static SESSIONS: Lazy<RwLock<HashMap<String, Arc<SshSession>>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 
-// Store jump host connections to keep them alive
-static JUMP_CONNECTIONS: Lazy<RwLock<HashMap<String, Arc<JumpHostConnection>>>> =
+static BROADCAST_GROUPS: Lazy<RwLock<HashMap<String, Vec<BroadcastMember>>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 
-struct JumpHostConnection {
-    #[allow(dead_code)]
-    handle: client::Handle<JumpHostHandler>,
+// Reconnects wait 1s, 2s, 4s... up to the cap, then give up
+const RECONNECT_ATTEMPTS: u32 = 10;
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Requests for the task that owns a shell channel
+enum ShellCommand {
+    /// Input, with where to report whether it was written if anyone asks
+    Data(Vec<u8>, Option<oneshot::Sender<Result<()>>>),
+    Resize(TerminalSize),
+    Close,
+}
+
+/// How a shell channel ended
+enum ShellEnd {
+    /// We closed it
+    Closed,
+    /// The server ended it, with the shell's exit status or signal if it
+    /// reported one
+    Exited(Option<ShellExit>),
+    /// The transport under it died, e.g. keepalives went unanswered
+    Lost,
 }
 
+/// One SSH connection to a server and the shells open on it. Every shell is
+/// its own PTY channel, so extra tabs and splits open without reconnecting.
 pub struct SshSession {
     session_id: String,
-    handle: client::Handle<ClientHandler>,
-    channel: Mutex<Option<Channel<client::Msg>>>,
-    #[allow(dead_code)]
-    output_tx: mpsc::UnboundedSender<Vec<u8>>,
-    output_rx: Mutex<Option<mpsc::UnboundedReceiver<Vec<u8>>>>,
-    // Keep jump host connection alive
-    #[allow(dead_code)]
-    jump_connection_id: Option<String>,
+    server: ServerConfig,
+    prompter: Option<Prompter>,
+    // Replaced when the session reconnects
+    connection: Mutex<Arc<PooledConnection>>,
+    // Open shells by channel ID; the first one shares the session's ID
+    shells: Mutex<HashMap<String, mpsc::UnboundedSender<ShellCommand>>>,
 }
 
-impl SshSession {
-    pub async fn connect(server: &ServerConfig) -> Result<Arc<Self>> {
-        let session_id = uuid::Uuid::new_v4().to_string();
-
-        // Check if we need to use a jump host
-        let (stream, jump_connection_id): (Box<dyn AsyncReadWrite>, Option<String>) =
-            if let Some(jump_host_id) = &server.jump_host {
-                let (stream, conn_id) = Self::connect_via_jump_host(jump_host_id, server).await?;
-                (Box::new(stream), Some(conn_id))
-            } else {
-                let stream = Self::create_connection(server).await?;
-                (Box::new(stream), None)
-            };
+/// What a shell reports besides its output
+pub enum SessionEvent {
+    State(SessionStateChange),
+    Exit(ShellExit),
+}
 
-        // SSH config
-        let config = client::Config {
-            inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
-            ..Default::default()
-        };
+/// Output and events of one shell, for the caller to relay
+pub struct ShellStreams {
+    pub output: mpsc::UnboundedReceiver<Vec<u8>>,
+    pub events: mpsc::UnboundedReceiver<SessionEvent>,
+}
 
-        let config = Arc::new(config);
-        let (output_tx, output_rx) = mpsc::unbounded_channel();
+/// The supervisor's side of a shell's streams
+struct Shell {
+    channel_id: String,
+    output_tx: mpsc::UnboundedSender<Vec<u8>>,
+    events_tx: mpsc::UnboundedSender<SessionEvent>,
+}
 
-        let handler = ClientHandler {
-            output_tx: output_tx.clone(),
-        };
+impl Shell {
+    fn notify(
+        &self,
+        state: SessionState,
+        reason: Option<String>,
+        attempt: Option<u32>,
+        retry_in: Option<u64>,
+    ) {
+        self.events_tx
+            .send(SessionEvent::State(SessionStateChange {
+                state,
+                reason,
+                attempt,
+                retry_in,
+            }))
+            .ok();
+    }
+}
 
-        let handle = client::connect_stream(config, stream, handler)
-            .await
-            .context("Failed to establish SSH connection")?;
+impl SshSession {
+    pub async fn connect(server: &ServerConfig, prompter: Option<Prompter>) -> Result<Arc<Self>> {
+        let session_id = uuid::Uuid::new_v4().to_string();
 
-        // Authenticate
-        let mut handle = handle;
-        Self::authenticate(&mut handle, server).await?;
+        let connection = pool::acquire(server, prompter.clone()).await?;
 
         let session = Arc::new(Self {
             session_id: session_id.clone(),
-            handle,
-            channel: Mutex::new(None),
-            output_tx,
-            output_rx: Mutex::new(Some(output_rx)),
-            jump_connection_id,
+            server: server.clone(),
+            prompter,
+            connection: Mutex::new(connection),
+            shells: Mutex::new(HashMap::new()),
         });
 
         SESSIONS.write().await.insert(session_id, session.clone());
@@ -85,333 +112,407 @@ impl SshSession {
         Ok(session)
     }
 
-    async fn connect_via_jump_host(
-        jump_host_id: &str,
-        target_server: &ServerConfig,
-    ) -> Result<(ChannelStream<client::Msg>, String)> {
-        // Get jump host server config
-        let jump_server = storage::get_server(jump_host_id)
-            .context("Jump host server not found")?;
-
-        // Connect to jump host
-        let jump_stream = Self::create_connection(&jump_server).await?;
-
-        let config = client::Config {
-            inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
-            ..Default::default()
-        };
-        let config = Arc::new(config);
-
-        let handler = JumpHostHandler;
-        let mut jump_handle = client::connect_stream(config, jump_stream, handler)
+    /// Opens a shell on a new channel of this session's connection
+    pub async fn open_shell(
+        self: &Arc<Self>,
+        channel_id: String,
+        size: TerminalSize,
+    ) -> Result<ShellStreams> {
+        let (channel, x11) = self.request_shell(&size).await?;
+
+        let (output_tx, output) = mpsc::unbounded_channel();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (shell_tx, shell_rx) = mpsc::unbounded_channel();
+        self.shells
+            .lock()
             .await
-            .context("Failed to connect to jump host")?;
-
-        // Authenticate to jump host
-        Self::authenticate_jump_host(&mut jump_handle, &jump_server).await?;
-
-        // Open direct-tcpip channel to target server
-        let channel = jump_handle
-            .channel_open_direct_tcpip(
-                &target_server.host,
-                target_server.port as u32,
-                "127.0.0.1",
-                0,
-            )
-            .await
-            .context("Failed to open tunnel through jump host")?;
+            .insert(channel_id.clone(), shell_tx);
 
-        // Store jump connection to keep it alive
-        let conn_id = uuid::Uuid::new_v4().to_string();
-        let jump_conn = Arc::new(JumpHostConnection {
-            handle: jump_handle,
-        });
-        JUMP_CONNECTIONS.write().await.insert(conn_id.clone(), jump_conn);
+        let shell = Shell {
+            channel_id,
+            output_tx,
+            events_tx,
+        };
+        shell.notify(SessionState::Connected, None, None, None);
+        tokio::spawn(self.clone().supervise(shell, channel, x11, shell_rx, size));
 
-        Ok((channel.into_stream(), conn_id))
+        Ok(ShellStreams { output, events })
     }
 
-    async fn authenticate_jump_host(
-        handle: &mut client::Handle<JumpHostHandler>,
-        server: &ServerConfig,
-    ) -> Result<()> {
-        match &server.auth {
-            AuthMethod::Password(password) => {
-                let auth_result = handle
-                    .authenticate_password(&server.username, password)
-                    .await
-                    .context("Jump host password authentication failed")?;
-
-                if !auth_result {
-                    anyhow::bail!("Jump host: Invalid username or password");
-                }
-            }
-            AuthMethod::PrivateKey { key, passphrase } => {
-                let passphrase_opt = passphrase.as_ref().filter(|p| !p.is_empty());
-                let key_pair = if let Some(passphrase) = passphrase_opt {
-                    decode_secret_key(key, Some(passphrase))
-                        .context("Failed to decode jump host private key with passphrase")?
-                } else {
-                    decode_secret_key(key, None)
-                        .context("Failed to decode jump host private key")?
-                };
-
-                let auth_result = handle
-                    .authenticate_publickey(&server.username, Arc::new(key_pair))
-                    .await
-                    .context("Jump host public key authentication failed")?;
-
-                if !auth_result {
-                    anyhow::bail!("Jump host: Public key authentication rejected");
-                }
-            }
-        }
-        Ok(())
-    }
+    async fn request_shell(
+        &self,
+        size: &TerminalSize,
+    ) -> Result<(Channel<client::Msg>, Option<x11::X11Cookie>)> {
+        let connection = self.connection.lock().await.clone();
+        let channel = connection
+            .open_session_channel()
+            .await
+            .context("Failed to open channel")?;
 
-    async fn create_connection(server: &ServerConfig) -> Result<TcpStream> {
-        let target_addr = format!("{}:{}", server.host, server.port);
+        channel
+            .request_pty(false, "xterm-256color", size.cols, size.rows, 0, 0, &[])
+            .await
+            .context("Failed to request PTY")?;
 
-        match &server.proxy {
-            Some(proxy) => Self::connect_via_proxy(proxy, &target_addr).await,
-            None => TcpStream::connect(&target_addr)
+        if self.server.forward_agent {
+            channel
+                .agent_forward(false)
                 .await
-                .context("Failed to connect to server"),
+                .context("Failed to request agent forwarding")?;
         }
+
+        let x11 = if self.server.forward_x11 {
+            Some(x11::request(&channel, connection.x11_displays()).await?)
+        } else {
+            None
+        };
+
+        channel
+            .request_shell(false)
+            .await
+            .context("Failed to request shell")?;
+
+        Ok((channel, x11))
     }
 
-    async fn connect_via_proxy(proxy: &ProxyConfig, target: &str) -> Result<TcpStream> {
-        let proxy_addr = format!("{}:{}", proxy.host, proxy.port);
-
-        match proxy.proxy_type {
-            ProxyType::Socks5 => {
-                let stream = if let (Some(user), Some(pass)) = (&proxy.username, &proxy.password) {
-                    Socks5Stream::connect_with_password(
-                        proxy_addr.as_str(),
-                        target,
-                        user,
-                        pass,
-                    )
-                    .await
-                    .context("Failed to connect via SOCKS5 proxy")?
-                } else {
-                    Socks5Stream::connect(proxy_addr.as_str(), target)
+    /// Runs a shell until it ends, reconnecting after a dropped connection
+    /// when the server allows it. The session is forgotten once its last
+    /// shell is over.
+    async fn supervise(
+        self: Arc<Self>,
+        shell: Shell,
+        mut channel: Channel<client::Msg>,
+        mut x11: Option<x11::X11Cookie>,
+        mut commands: mpsc::UnboundedReceiver<ShellCommand>,
+        mut size: TerminalSize,
+    ) {
+        let reason = loop {
+            let connection = self.connection.lock().await.clone();
+            let end = run_shell(
+                channel,
+                &connection,
+                &mut commands,
+                &shell.output_tx,
+                &mut size,
+            );
+            match end.await {
+                ShellEnd::Closed => break None,
+                ShellEnd::Exited(None) => break Some("Remote shell exited".to_string()),
+                ShellEnd::Exited(Some(exit)) => {
+                    let reason = match (&exit.exit_code, &exit.signal) {
+                        (_, Some(signal)) => format!("Remote shell killed by SIG{}", signal),
+                        (Some(code), None) => format!("Remote shell exited with status {}", code),
+                        (None, None) => "Remote shell exited".to_string(),
+                    };
+                    // Sent before the closed state so the terminal can show it
+                    shell.events_tx.send(SessionEvent::Exit(exit)).ok();
+                    break Some(reason);
+                }
+                ShellEnd::Lost => {
+                    let mut reason = self
+                        .connection
+                        .lock()
                         .await
-                        .context("Failed to connect via SOCKS5 proxy")?
-                };
-                Ok(stream.into_inner())
-            }
-            ProxyType::Http => {
-                // HTTP CONNECT proxy
-                let mut stream = TcpStream::connect(&proxy_addr)
-                    .await
-                    .context("Failed to connect to HTTP proxy")?;
-
-                let connect_request = if let (Some(user), Some(pass)) = (&proxy.username, &proxy.password) {
-                    let credentials = base64::Engine::encode(
-                        &base64::engine::general_purpose::STANDARD,
-                        format!("{}:{}", user, pass),
-                    );
-                    format!(
-                        "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Basic {}\r\n\r\n",
-                        target, target, credentials
-                    )
-                } else {
-                    format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target)
-                };
-
-                stream.write_all(connect_request.as_bytes()).await?;
-
-                let mut response = vec![0u8; 1024];
-                let n = stream.read(&mut response).await?;
-                let response_str = String::from_utf8_lossy(&response[..n]);
-
-                if !response_str.contains("200") {
-                    anyhow::bail!("HTTP proxy connection failed: {}", response_str);
+                        .close_reason()
+                        .unwrap_or_else(|| "Connection lost".to_string());
+                    if !self.server.auto_reconnect {
+                        break Some(reason);
+                    }
+                    match self
+                        .reconnect(&shell, &mut commands, &mut size, &mut reason)
+                        .await
+                    {
+                        Reconnect::Reopened(reopened, reopened_x11) => {
+                            shell.notify(SessionState::Connected, None, None, None);
+                            channel = reopened;
+                            x11 = reopened_x11;
+                        }
+                        Reconnect::Cancelled => break None,
+                        Reconnect::GaveUp => break Some(reason),
+                    }
                 }
-
-                Ok(stream)
             }
+        };
+        shell.notify(SessionState::Closed, reason, None, None);
+
+        leave_broadcast_groups(&self.session_id, &shell.channel_id).await;
+        let mut shells = self.shells.lock().await;
+        shells.remove(&shell.channel_id);
+        // X11 clients can no longer connect through this shell
+        drop(x11);
+        if shells.is_empty() {
+            remove_session(&self.session_id).await;
         }
     }
 
-    async fn authenticate(handle: &mut client::Handle<ClientHandler>, server: &ServerConfig) -> Result<()> {
-        match &server.auth {
-            AuthMethod::Password(password) => {
-                let auth_result = handle
-                    .authenticate_password(&server.username, password)
-                    .await
-                    .context("Password authentication failed")?;
-
-                if !auth_result {
-                    anyhow::bail!("Invalid username or password");
+    /// Reconnects with backoff and reopens the shell. Input typed meanwhile
+    /// is dropped rather than replayed into the new shell.
+    async fn reconnect(
+        &self,
+        shell: &Shell,
+        commands: &mut mpsc::UnboundedReceiver<ShellCommand>,
+        size: &mut TerminalSize,
+        reason: &mut String,
+    ) -> Reconnect {
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            let delay = RECONNECT_MAX_DELAY.min(Duration::from_secs(1 << (attempt - 1).min(5)));
+            shell.notify(
+                SessionState::Reconnecting,
+                Some(reason.clone()),
+                Some(attempt),
+                Some(delay.as_secs()),
+            );
+
+            let wait = tokio::time::sleep(delay);
+            tokio::pin!(wait);
+            loop {
+                tokio::select! {
+                    () = &mut wait => break,
+                    command = commands.recv() => match command {
+                        Some(ShellCommand::Data(..)) => {}
+                        Some(ShellCommand::Resize(new_size)) => *size = new_size,
+                        Some(ShellCommand::Close) | None => return Reconnect::Cancelled,
+                    },
                 }
             }
-            AuthMethod::PrivateKey { key, passphrase } => {
-                // Treat empty passphrase as None
-                let passphrase_opt = passphrase.as_ref().filter(|p| !p.is_empty());
-                let key_pair = if let Some(passphrase) = passphrase_opt {
-                    decode_secret_key(key, Some(passphrase))
-                        .context("Failed to decode private key with passphrase")?
-                } else {
-                    decode_secret_key(key, None)
-                        .context("Failed to decode private key")?
-                };
-
-                let auth_result = handle
-                    .authenticate_publickey(&server.username, Arc::new(key_pair))
-                    .await
-                    .context("Public key authentication failed")?;
-
-                if !auth_result {
-                    anyhow::bail!("Public key authentication rejected");
+
+            shell.notify(SessionState::Connecting, None, Some(attempt), None);
+            let (channel, x11) = match self.reopen(size).await {
+                Ok(reopened) => reopened,
+                Err(e) => {
+                    *reason = format!("{:#}", e);
+                    continue;
+                }
+            };
+
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    ShellCommand::Data(..) => {}
+                    ShellCommand::Resize(new_size) => *size = new_size,
+                    ShellCommand::Close => {
+                        channel.close().await.ok();
+                        return Reconnect::Cancelled;
+                    }
                 }
             }
+            channel.window_change(size.cols, size.rows, 0, 0).await.ok();
+            return Reconnect::Reopened(channel, x11);
         }
-
-        Ok(())
+        Reconnect::GaveUp
     }
 
-    pub async fn open_shell(&self, size: TerminalSize) -> Result<()> {
-        let channel = self
-            .handle
-            .channel_open_session()
-            .await
-            .context("Failed to open channel")?;
-
-        channel
-            .request_pty(
-                false,
-                "xterm-256color",
-                size.cols,
-                size.rows,
-                0,
-                0,
-                &[],
-            )
-            .await
-            .context("Failed to request PTY")?;
-
-        channel
-            .request_shell(false)
-            .await
-            .context("Failed to request shell")?;
-
-        *self.channel.lock().await = Some(channel);
+    /// Gets a live connection, shared with the session's other shells that
+    /// are reconnecting too, and opens a shell on it
+    async fn reopen(
+        &self,
+        size: &TerminalSize,
+    ) -> Result<(Channel<client::Msg>, Option<x11::X11Cookie>)> {
+        let connection = pool::acquire(&self.server, self.prompter.clone()).await?;
+        *self.connection.lock().await = connection;
+        self.request_shell(size).await
+    }
 
-        Ok(())
+    async fn send(&self, channel_id: &str, command: ShellCommand) -> Result<()> {
+        let shells = self.shells.lock().await;
+        let shell_tx = shells
+            .get(channel_id)
+            .ok_or_else(|| anyhow!("Channel not found"))?;
+        shell_tx
+            .send(command)
+            .map_err(|_| anyhow!("Shell channel is closed"))
     }
 
-    pub async fn write(&self, data: &[u8]) -> Result<()> {
-        let channel_guard = self.channel.lock().await;
-        if let Some(channel) = channel_guard.as_ref() {
-            channel.data(data).await?;
-        }
-        Ok(())
+    pub async fn write(&self, channel_id: &str, data: &[u8]) -> Result<()> {
+        self.send(channel_id, ShellCommand::Data(data.to_vec(), None))
+            .await
     }
 
-    pub async fn resize(&self, size: TerminalSize) -> Result<()> {
-        let channel_guard = self.channel.lock().await;
-        if let Some(channel) = channel_guard.as_ref() {
-            channel
-                .window_change(size.cols, size.rows, 0, 0)
-                .await?;
-        }
-        Ok(())
+    /// Like `write`, but waits until the data went out on the channel
+    async fn write_confirmed(&self, channel_id: &str, data: &[u8]) -> Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.send(channel_id, ShellCommand::Data(data.to_vec(), Some(done_tx)))
+            .await?;
+        done_rx
+            .await
+            .map_err(|_| anyhow!("Shell dropped the input while reconnecting or closing"))?
     }
 
-    pub async fn take_output_receiver(&self) -> Option<mpsc::UnboundedReceiver<Vec<u8>>> {
-        self.output_rx.lock().await.take()
+    pub async fn resize(&self, channel_id: &str, size: TerminalSize) -> Result<()> {
+        self.send(channel_id, ShellCommand::Resize(size)).await
     }
 
     pub fn session_id(&self) -> &str {
         &self.session_id
     }
 
+    /// Closes one shell; the session goes away with its last one
+    pub async fn close_channel(&self, channel_id: &str) -> Result<()> {
+        self.send(channel_id, ShellCommand::Close).await
+    }
+
     pub async fn close(&self) -> Result<()> {
-        let channel = self.channel.lock().await.take();
-        if let Some(channel) = channel {
-            channel.eof().await.ok();
-            channel.close().await.ok();
+        for (_, shell_tx) in self.shells.lock().await.drain() {
+            shell_tx.send(ShellCommand::Close).ok();
         }
-        self.handle.disconnect(Disconnect::ByApplication, "", "en").await?;
+        // The pooled connection closes once its last session is gone
         SESSIONS.write().await.remove(&self.session_id);
 
-        // Clean up jump connection if exists
-        if let Some(conn_id) = &self.jump_connection_id {
-            JUMP_CONNECTIONS.write().await.remove(conn_id);
-        }
-
         Ok(())
     }
 }
 
+enum Reconnect {
+    Reopened(Channel<client::Msg>, Option<x11::X11Cookie>),
+    /// The terminal was closed while waiting
+    Cancelled,
+    GaveUp,
+}
+
 pub async fn get_session(session_id: &str) -> Option<Arc<SshSession>> {
     SESSIONS.read().await.get(session_id).cloned()
 }
 
 pub async fn remove_session(session_id: &str) {
-    if let Some(session) = SESSIONS.write().await.remove(session_id) {
-        // Clean up jump connection if exists
-        if let Some(conn_id) = &session.jump_connection_id {
-            JUMP_CONNECTIONS.write().await.remove(conn_id);
-        }
-    }
+    SESSIONS.write().await.remove(session_id);
 }
 
-// Trait for async read/write streams
-trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
-impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadWrite for T {}
-
-struct ClientHandler {
-    output_tx: mpsc::UnboundedSender<Vec<u8>>,
+/// Groups terminals so input typed once goes to all of them
+pub async fn create_broadcast_group(members: Vec<BroadcastMember>) -> String {
+    let group_id = uuid::Uuid::new_v4().to_string();
+    BROADCAST_GROUPS
+        .write()
+        .await
+        .insert(group_id.clone(), members);
+    group_id
 }
 
-#[async_trait]
-impl client::Handler for ClientHandler {
-    type Error = anyhow::Error;
+pub async fn remove_broadcast_group(group_id: &str) {
+    BROADCAST_GROUPS.write().await.remove(group_id);
+}
 
-    async fn check_server_key(
-        &mut self,
-        _server_public_key: &key::PublicKey,
-    ) -> Result<bool, Self::Error> {
-        // TODO: Implement proper host key verification
-        Ok(true)
+/// Takes a shell that ended out of every group it was in
+async fn leave_broadcast_groups(session_id: &str, channel_id: &str) {
+    for members in BROADCAST_GROUPS.write().await.values_mut() {
+        members.retain(|member| {
+            member.session_id != session_id
+                || member.channel_id.as_deref().unwrap_or(&member.session_id) != channel_id
+        });
     }
+}
 
-    async fn data(
-        &mut self,
-        _channel: ChannelId,
-        data: &[u8],
-        _session: &mut client::Session,
-    ) -> Result<(), Self::Error> {
-        self.output_tx.send(data.to_vec()).ok();
-        Ok(())
+/// Writes to every member of a group at once. A member that can't be reached
+/// doesn't hold up the others and is reported back instead.
+pub async fn broadcast(group_id: &str, data: &[u8]) -> Result<Vec<BroadcastFailure>> {
+    let members = BROADCAST_GROUPS
+        .read()
+        .await
+        .get(group_id)
+        .cloned()
+        .ok_or_else(|| anyhow!("Broadcast group not found"))?;
+
+    let mut writes = JoinSet::new();
+    for member in members {
+        let data = data.to_vec();
+        writes.spawn(async move {
+            let channel_id = member.channel_id.as_deref().unwrap_or(&member.session_id);
+            let result = match get_session(&member.session_id).await {
+                Some(session) => session.write_confirmed(channel_id, &data).await,
+                None => Err(anyhow!("Session not found")),
+            };
+            result.err().map(|e| BroadcastFailure {
+                session_id: member.session_id,
+                channel_id: member.channel_id,
+                error: e.to_string(),
+            })
+        });
     }
 
-    async fn extended_data(
-        &mut self,
-        _channel: ChannelId,
-        _ext: u32,
-        data: &[u8],
-        _session: &mut client::Session,
-    ) -> Result<(), Self::Error> {
-        self.output_tx.send(data.to_vec()).ok();
-        Ok(())
+    let mut failures = Vec::new();
+    while let Some(write) = writes.join_next().await {
+        failures.extend(write?);
     }
+    Ok(failures)
 }
 
-struct JumpHostHandler;
-
-#[async_trait]
-impl client::Handler for JumpHostHandler {
-    type Error = anyhow::Error;
+/// Owns a shell channel: forwards its output and applies writes and resizes.
+/// Each terminal reads its own channel, so several can share a connection.
+async fn run_shell(
+    mut channel: Channel<client::Msg>,
+    connection: &PooledConnection,
+    commands: &mut mpsc::UnboundedReceiver<ShellCommand>,
+    output_tx: &mpsc::UnboundedSender<Vec<u8>>,
+    size: &mut TerminalSize,
+) -> ShellEnd {
+    let mut exit = ShellExit::default();
+    let mut exited = false;
+    let mut eof = false;
+    loop {
+        tokio::select! {
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Data { data }) | Some(ChannelMsg::ExtendedData { data, .. }) => {
+                    output_tx.send(data.to_vec()).ok();
+                }
+                Some(ChannelMsg::ExitStatus { exit_status }) => {
+                    exit.exit_code = Some(exit_status);
+                    exited = true;
+                }
+                Some(ChannelMsg::ExitSignal {
+                    signal_name,
+                    core_dumped,
+                    error_message,
+                    ..
+                }) => {
+                    exit.signal = Some(signal_name_of(&signal_name));
+                    exit.core_dumped = core_dumped;
+                    exit.error_message = Some(error_message).filter(|m| !m.is_empty());
+                    exited = true;
+                }
+                Some(ChannelMsg::Eof) => eof = true,
+                Some(_) => {}
+                // russh drops the channel rather than passing on the server's
+                // close, so a channel that ends on a live connection was
+                // closed by the server too
+                None if exited || !connection.is_closed().await => {
+                    return ShellEnd::Exited(exited.then_some(exit))
+                }
+                None => return ShellEnd::Lost,
+            },
+            command = commands.recv() => match command {
+                Some(ShellCommand::Data(data, done)) => {
+                    // Otherwise a failed write shows up as the channel ending
+                    let written = channel.data(&data[..]).await;
+                    if let Some(done) = done {
+                        done.send(written.context("Failed to write to the shell")).ok();
+                    }
+                }
+                Some(ShellCommand::Resize(new_size)) => {
+                    channel.window_change(new_size.cols, new_size.rows, 0, 0).await.ok();
+                    *size = new_size;
+                }
+                // A shell that already exited still reports how
+                Some(ShellCommand::Close) | None if exited => {
+                    channel.close().await.ok();
+                    return ShellEnd::Exited(Some(exit));
+                }
+                Some(ShellCommand::Close) | None => {
+                    channel.eof().await.ok();
+                    channel.close().await.ok();
+                    return ShellEnd::Closed;
+                }
+            },
+        }
+        // The shell is gone and has nothing more to say, whether or not the
+        // server gets round to closing the channel
+        if exited && eof {
+            channel.close().await.ok();
+            return ShellEnd::Exited(Some(exit));
+        }
+    }
+}
 
-    async fn check_server_key(
-        &mut self,
-        _server_public_key: &key::PublicKey,
-    ) -> Result<bool, Self::Error> {
-        // TODO: Implement proper host key verification
-        Ok(true)
+pub(crate) fn signal_name_of(signal: &Sig) -> String {
+    match signal {
+        Sig::Custom(name) => name.clone(),
+        other => format!("{:?}", other),
     }
 }
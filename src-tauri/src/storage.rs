@@ -1,4 +1,7 @@
-use crate::models::ServerConfig;
+use crate::models::{
+    chrono_timestamp, DynamicForward, HostKeyMarker, KnownHost, KnownHostsImport, LocalForward,
+    PartialTransfer, RemoteForward, ServerConfig, SkippedLine, TransferQueueState,
+};
 use aes_gcm::{
     aead::{Aead, KeyInit},
     Aes256Gcm, Nonce,
@@ -10,12 +13,15 @@ use parking_lot::RwLock;
 use rand::Rng;
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 static STORAGE: Lazy<RwLock<Storage>> = Lazy::new(|| RwLock::new(Storage::new()));
 
 pub struct Storage {
     servers: HashMap<String, ServerConfig>,
+    known_hosts: HashMap<String, KnownHost>,
+    // Sources of unfinished transfers, so resuming can tell if they changed
+    partial_transfers: HashMap<String, PartialTransfer>,
     data_dir: PathBuf,
     encryption_key: [u8; 32],
 }
@@ -33,15 +39,19 @@ impl Storage {
 
         let mut storage = Self {
             servers: HashMap::new(),
+            known_hosts: HashMap::new(),
+            partial_transfers: HashMap::new(),
             data_dir,
             encryption_key,
         };
 
         storage.load().ok();
+        storage.load_known_hosts().ok();
+        storage.load_partial_transfers().ok();
         storage
     }
 
-    fn get_or_create_key(data_dir: &PathBuf) -> [u8; 32] {
+    fn get_or_create_key(data_dir: &Path) -> [u8; 32] {
         let key_file = data_dir.join(".key");
 
         if let Ok(key_data) = fs::read(&key_file) {
@@ -55,7 +65,7 @@ impl Storage {
         // Generate new key
         let mut key = [0u8; 32];
         rand::thread_rng().fill(&mut key);
-        fs::write(&key_file, &key).ok();
+        fs::write(&key_file, key).ok();
 
         // Set restrictive permissions on Unix
         #[cfg(unix)]
@@ -146,6 +156,65 @@ impl Storage {
         Ok(())
     }
 
+    fn known_hosts_file(&self) -> PathBuf {
+        self.data_dir.join("known_hosts.json")
+    }
+
+    fn load_known_hosts(&mut self) -> Result<()> {
+        let path = self.known_hosts_file();
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(&path)?;
+        let entries: Vec<KnownHost> = serde_json::from_str(&content)?;
+
+        self.known_hosts = entries
+            .into_iter()
+            .map(|entry| (known_host_entry_id(&entry), entry))
+            .collect();
+
+        Ok(())
+    }
+
+    fn save_known_hosts(&self) -> Result<()> {
+        // Host keys are public, no need to encrypt them
+        let mut entries: Vec<&KnownHost> = self.known_hosts.values().collect();
+        entries.sort_by(|a, b| (&a.host, a.port, &a.key_type).cmp(&(&b.host, b.port, &b.key_type)));
+
+        let content = serde_json::to_string_pretty(&entries)?;
+        fs::write(self.known_hosts_file(), content)?;
+
+        Ok(())
+    }
+
+    fn transfer_queue_file(&self) -> PathBuf {
+        self.data_dir.join("transfer_queue.json")
+    }
+
+    fn partial_transfers_file(&self) -> PathBuf {
+        self.data_dir.join("partial_transfers.json")
+    }
+
+    fn load_partial_transfers(&mut self) -> Result<()> {
+        let path = self.partial_transfers_file();
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(&path)?;
+        self.partial_transfers = serde_json::from_str(&content)?;
+
+        Ok(())
+    }
+
+    fn save_partial_transfers(&self) -> Result<()> {
+        let content = serde_json::to_string_pretty(&self.partial_transfers)?;
+        fs::write(self.partial_transfers_file(), content)?;
+
+        Ok(())
+    }
+
     fn encrypt_server(&self, server: &ServerConfig) -> Result<EncryptedServerConfig> {
         use crate::models::AuthMethod;
 
@@ -153,12 +222,21 @@ impl Storage {
             AuthMethod::Password(pwd) => {
                 EncryptedAuth::Password(self.encrypt(pwd)?)
             }
-            AuthMethod::PrivateKey { key, passphrase } => {
-                EncryptedAuth::PrivateKey {
-                    key: self.encrypt(key)?,
-                    passphrase: passphrase.as_ref().map(|p| self.encrypt(p)).transpose()?,
-                }
-            }
+            AuthMethod::PrivateKey {
+                key,
+                passphrase,
+                certificate,
+            } => EncryptedAuth::PrivateKey {
+                key: self.encrypt(key)?,
+                passphrase: passphrase.as_ref().map(|p| self.encrypt(p)).transpose()?,
+                // Certificates only hold public data
+                certificate: certificate.clone(),
+            },
+            // Agent fingerprints are not secret
+            AuthMethod::Agent { fingerprint } => EncryptedAuth::Agent {
+                fingerprint: fingerprint.clone(),
+            },
+            AuthMethod::KeyboardInteractive => EncryptedAuth::KeyboardInteractive,
         };
 
         let encrypted_proxy = server.proxy.as_ref().map(|p| {
@@ -171,6 +249,24 @@ impl Storage {
             }
         });
 
+        let dynamic_forwards = server
+            .dynamic_forwards
+            .iter()
+            .map(|rule| {
+                Ok(EncryptedDynamicForward {
+                    id: rule.id.clone(),
+                    bind_address: rule.bind_address.clone(),
+                    local_port: rule.local_port,
+                    username: rule.username.clone(),
+                    password: rule
+                        .password
+                        .as_ref()
+                        .map(|p| self.encrypt(p))
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
         Ok(EncryptedServerConfig {
             id: server.id.clone(),
             name: server.name.clone(),
@@ -179,7 +275,17 @@ impl Storage {
             username: server.username.clone(),
             auth: encrypted_auth,
             proxy: encrypted_proxy,
-            jump_host: server.jump_host.clone(),
+            jump_hosts: server.jump_hosts.clone(),
+            local_forwards: server.local_forwards.clone(),
+            remote_forwards: server.remote_forwards.clone(),
+            dynamic_forwards,
+            forward_agent: server.forward_agent,
+            forward_x11: server.forward_x11,
+            keepalive_interval: server.keepalive_interval,
+            keepalive_max: server.keepalive_max,
+            auto_reconnect: server.auto_reconnect,
+            tags: server.tags.clone(),
+            max_transfers: server.max_transfers,
             notes: server.notes.clone(),
             created_at: server.created_at,
             updated_at: server.updated_at,
@@ -193,12 +299,19 @@ impl Storage {
             EncryptedAuth::Password(pwd) => {
                 AuthMethod::Password(self.decrypt(pwd)?)
             }
-            EncryptedAuth::PrivateKey { key, passphrase } => {
-                AuthMethod::PrivateKey {
-                    key: self.decrypt(key)?,
-                    passphrase: passphrase.as_ref().map(|p| self.decrypt(p)).transpose()?,
-                }
-            }
+            EncryptedAuth::PrivateKey {
+                key,
+                passphrase,
+                certificate,
+            } => AuthMethod::PrivateKey {
+                key: self.decrypt(key)?,
+                passphrase: passphrase.as_ref().map(|p| self.decrypt(p)).transpose()?,
+                certificate: certificate.clone(),
+            },
+            EncryptedAuth::Agent { fingerprint } => AuthMethod::Agent {
+                fingerprint: fingerprint.clone(),
+            },
+            EncryptedAuth::KeyboardInteractive => AuthMethod::KeyboardInteractive,
         };
 
         let proxy = encrypted.proxy.as_ref().map(|p| {
@@ -211,6 +324,24 @@ impl Storage {
             }
         });
 
+        let dynamic_forwards = encrypted
+            .dynamic_forwards
+            .iter()
+            .map(|rule| {
+                Ok(DynamicForward {
+                    id: rule.id.clone(),
+                    bind_address: rule.bind_address.clone(),
+                    local_port: rule.local_port,
+                    username: rule.username.clone(),
+                    password: rule
+                        .password
+                        .as_ref()
+                        .map(|p| self.decrypt(p))
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
         Ok(ServerConfig {
             id: encrypted.id.clone(),
             name: encrypted.name.clone(),
@@ -219,7 +350,17 @@ impl Storage {
             username: encrypted.username.clone(),
             auth,
             proxy,
-            jump_host: encrypted.jump_host.clone(),
+            jump_hosts: encrypted.jump_hosts.clone(),
+            local_forwards: encrypted.local_forwards.clone(),
+            remote_forwards: encrypted.remote_forwards.clone(),
+            dynamic_forwards,
+            forward_agent: encrypted.forward_agent,
+            forward_x11: encrypted.forward_x11,
+            keepalive_interval: encrypted.keepalive_interval,
+            keepalive_max: encrypted.keepalive_max,
+            auto_reconnect: encrypted.auto_reconnect,
+            tags: encrypted.tags.clone(),
+            max_transfers: encrypted.max_transfers,
             notes: encrypted.notes.clone(),
             created_at: encrypted.created_at,
             updated_at: encrypted.updated_at,
@@ -236,8 +377,32 @@ struct EncryptedServerConfig {
     username: String,
     auth: EncryptedAuth,
     proxy: Option<EncryptedProxy>,
+    #[serde(
+        default,
+        alias = "jump_host",
+        deserialize_with = "crate::models::deserialize_jump_hosts"
+    )]
+    jump_hosts: Vec<String>,
+    #[serde(default)]
+    local_forwards: Vec<LocalForward>,
+    #[serde(default)]
+    remote_forwards: Vec<RemoteForward>,
+    #[serde(default)]
+    dynamic_forwards: Vec<EncryptedDynamicForward>,
+    #[serde(default)]
+    forward_agent: bool,
+    #[serde(default)]
+    forward_x11: bool,
+    #[serde(default = "crate::models::default_keepalive_interval")]
+    keepalive_interval: u32,
+    #[serde(default = "crate::models::default_keepalive_max")]
+    keepalive_max: u32,
+    #[serde(default)]
+    auto_reconnect: bool,
     #[serde(default)]
-    jump_host: Option<String>,
+    tags: Vec<String>,
+    #[serde(default = "crate::models::default_max_transfers")]
+    max_transfers: u32,
     notes: Option<String>,
     created_at: i64,
     updated_at: i64,
@@ -250,7 +415,14 @@ enum EncryptedAuth {
     PrivateKey {
         key: String,
         passphrase: Option<String>,
+        #[serde(default)]
+        certificate: Option<String>,
     },
+    Agent {
+        #[serde(default)]
+        fingerprint: Option<String>,
+    },
+    KeyboardInteractive,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -262,6 +434,15 @@ struct EncryptedProxy {
     password: Option<String>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
+struct EncryptedDynamicForward {
+    id: String,
+    bind_address: String,
+    local_port: u16,
+    username: Option<String>,
+    password: Option<String>,
+}
+
 // Public API
 pub fn get_all_servers() -> Vec<ServerConfig> {
     STORAGE.read().servers.values().cloned().collect()
@@ -296,6 +477,447 @@ pub fn delete_server(id: &str) -> Result<()> {
     storage.save()
 }
 
+// Partial transfers
+
+/// What a transfer recorded about its partial file when it last wrote to it
+pub fn partial_transfer(key: &str) -> Option<PartialTransfer> {
+    STORAGE.read().partial_transfers.get(key).copied()
+}
+
+pub fn record_partial_transfer(key: &str, partial: PartialTransfer) -> Result<()> {
+    let mut storage = STORAGE.write();
+    storage.partial_transfers.insert(key.to_string(), partial);
+    storage.save_partial_transfers()
+}
+
+/// Moves the end of the gapless prefix of a recorded partial file
+pub fn update_partial_transfer(key: &str, written: u64) -> Result<()> {
+    let mut storage = STORAGE.write();
+    let Some(partial) = storage.partial_transfers.get_mut(key) else {
+        return Ok(());
+    };
+    partial.written = written;
+    storage.save_partial_transfers()
+}
+
+pub fn forget_partial_transfer(key: &str) -> Result<()> {
+    let mut storage = STORAGE.write();
+    if storage.partial_transfers.remove(key).is_some() {
+        storage.save_partial_transfers()?;
+    }
+    Ok(())
+}
+
+// Transfer queue
+
+pub fn load_transfer_queue() -> Result<Option<TransferQueueState>> {
+    let path = STORAGE.read().transfer_queue_file();
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read_to_string(&path)?;
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+pub fn save_transfer_queue(queue: &TransferQueueState) -> Result<()> {
+    let content = serde_json::to_string_pretty(queue)?;
+    fs::write(STORAGE.read().transfer_queue_file(), content)?;
+
+    Ok(())
+}
+
+// Known hosts
+
+/// Result of looking up a server's host key in the known hosts store
+pub enum HostKeyStatus {
+    /// The presented key matches a stored one
+    Trusted,
+    /// No key has been stored for the host yet
+    Unknown,
+    /// Other keys are stored for the host, of this type or another. Keys of
+    /// the presented type come first.
+    Changed(Vec<KnownHost>),
+    /// The presented key is marked `@revoked`
+    Revoked,
+}
+
+fn known_host_id(host: &str, port: u16, key_type: &str) -> String {
+    format!("{}:{} {}", host.to_lowercase(), port, key_type)
+}
+
+fn known_host_entry_id(entry: &KnownHost) -> String {
+    let id = known_host_id(&entry.host, entry.port, &entry.key_type);
+    match entry.marker {
+        None => id,
+        // A host may have several CA or revoked keys of the same type
+        Some(HostKeyMarker::CertAuthority) => {
+            format!("@cert-authority {} {}", id, entry.public_key)
+        }
+        Some(HostKeyMarker::Revoked) => format!("@revoked {} {}", id, entry.public_key),
+    }
+}
+
+/// Name used for a host in known_hosts files, `[host]:port` for non-standard ports
+fn known_hosts_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_lowercase()
+    } else {
+        format!("[{}]:{}", host.to_lowercase(), port)
+    }
+}
+
+fn host_key_matches(entry: &KnownHost, host: &str, port: u16) -> bool {
+    if !entry.pattern {
+        return entry.port == port && entry.host.eq_ignore_ascii_case(host);
+    }
+
+    let name = known_hosts_name(host, port);
+    let mut matched = false;
+    for pattern in entry.host.split(',') {
+        if let Some(hashed) = pattern.strip_prefix("|1|") {
+            matched |= hashed_host_matches(hashed, &name);
+        } else if let Some(negated) = pattern.strip_prefix('!') {
+            // A matching negated pattern excludes the host regardless of the others
+            if wildcard_matches(&negated.to_lowercase(), &name) {
+                return false;
+            }
+        } else {
+            matched |= wildcard_matches(&pattern.to_lowercase(), &name);
+        }
+    }
+    matched
+}
+
+/// Checks a `salt|hash` pair from a hashed known_hosts entry (`|1|salt|hash`)
+fn hashed_host_matches(hashed: &str, name: &str) -> bool {
+    use hmac::{Hmac, Mac};
+
+    let Some((salt, hash)) = hashed.split_once('|') else {
+        return false;
+    };
+    let (Ok(salt), Ok(hash)) = (BASE64.decode(salt), BASE64.decode(hash)) else {
+        return false;
+    };
+    let Ok(mut mac) = <Hmac<sha1::Sha1> as Mac>::new_from_slice(&salt) else {
+        return false;
+    };
+    mac.update(name.as_bytes());
+    mac.verify_slice(&hash).is_ok()
+}
+
+fn hash_host_name(name: &str) -> String {
+    use hmac::{Hmac, Mac};
+
+    let mut salt = [0u8; 20];
+    rand::thread_rng().fill(&mut salt);
+    let mut mac =
+        <Hmac<sha1::Sha1> as Mac>::new_from_slice(&salt).expect("HMAC accepts any key size");
+    mac.update(name.as_bytes());
+
+    format!(
+        "|1|{}|{}",
+        BASE64.encode(salt),
+        BASE64.encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Matches OpenSSH host patterns, where `*` matches any run of characters and `?` exactly one
+fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn host_key_fingerprint(public_key: &str) -> Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let blob = BASE64
+        .decode(public_key)
+        .context("Invalid public key encoding")?;
+    let digest = Sha256::digest(&blob);
+    Ok(format!(
+        "SHA256:{}",
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest)
+    ))
+}
+
+pub fn check_host_key(host: &str, port: u16, key_type: &str, public_key: &str) -> HostKeyStatus {
+    let storage = STORAGE.read();
+    let matching: Vec<&KnownHost> = storage
+        .known_hosts
+        .values()
+        .filter(|entry| host_key_matches(entry, host, port))
+        .collect();
+
+    if matching
+        .iter()
+        .any(|entry| entry.marker == Some(HostKeyMarker::Revoked) && entry.public_key == public_key)
+    {
+        return HostKeyStatus::Revoked;
+    }
+
+    // A known host offering a key of another type is as suspect as one whose
+    // key changed, it must not pass as a first connection
+    let mut stored: Vec<KnownHost> = matching
+        .into_iter()
+        .filter(|entry| entry.marker.is_none())
+        .cloned()
+        .collect();
+    if stored.is_empty() {
+        return HostKeyStatus::Unknown;
+    }
+    if stored
+        .iter()
+        .any(|entry| entry.key_type == key_type && entry.public_key == public_key)
+    {
+        return HostKeyStatus::Trusted;
+    }
+    stored.sort_by_key(|entry| entry.key_type != key_type);
+    HostKeyStatus::Changed(stored)
+}
+
+pub fn get_known_hosts() -> Vec<KnownHost> {
+    STORAGE.read().known_hosts.values().cloned().collect()
+}
+
+/// Store a host key as trusted, replacing any previous key of the same type,
+/// hashed entries for the host included. Wildcard patterns also cover other
+/// hosts, so a key stored under one of them stays.
+pub fn trust_host_key(mut entry: KnownHost) -> Result<KnownHost> {
+    let mut storage = STORAGE.write();
+    storage.known_hosts.retain(|_, known| {
+        !(known.host.starts_with("|1|")
+            && known.marker.is_none()
+            && known.key_type == entry.key_type
+            && host_key_matches(known, &entry.host, entry.port))
+    });
+
+    let id = known_host_entry_id(&entry);
+    if entry.added_at == 0 {
+        entry.added_at = chrono_timestamp();
+    }
+
+    storage.known_hosts.insert(id, entry.clone());
+    storage.save_known_hosts()?;
+
+    Ok(entry)
+}
+
+/// Trust `public_key` for a host and key type, e.g. after checking a changed
+/// key out of band. A revocation of that same key is lifted.
+pub fn accept_host_key(
+    host: &str,
+    port: u16,
+    key_type: &str,
+    public_key: &str,
+) -> Result<KnownHost> {
+    let entry = KnownHost {
+        host: host.to_string(),
+        port,
+        key_type: key_type.to_string(),
+        fingerprint: host_key_fingerprint(public_key)?,
+        public_key: public_key.to_string(),
+        marker: None,
+        pattern: false,
+        comment: None,
+        added_at: 0,
+    };
+    let revoked = KnownHost {
+        marker: Some(HostKeyMarker::Revoked),
+        ..entry.clone()
+    };
+    STORAGE
+        .write()
+        .known_hosts
+        .remove(&known_host_entry_id(&revoked));
+
+    trust_host_key(entry)
+}
+
+/// Stop trusting the key stored for a host and key type. The key is kept
+/// marked `@revoked`, so a server presenting it again is refused instead of
+/// being trusted anew; `forget_host_key` undoes that.
+pub fn revoke_host_key(host: &str, port: u16, key_type: &str) -> Result<()> {
+    let mut storage = STORAGE.write();
+    let mut entry = storage
+        .known_hosts
+        .remove(&known_host_id(host, port, key_type))
+        .context("No trusted key stored for this host and key type")?;
+
+    entry.marker = Some(HostKeyMarker::Revoked);
+    let id = known_host_entry_id(&entry);
+    storage.known_hosts.insert(id, entry);
+    storage.save_known_hosts()
+}
+
+/// Remove every entry stored for a host and key type, revoked and CA keys
+/// included, so the next connection starts from scratch
+pub fn forget_host_key(host: &str, port: u16, key_type: &str) -> Result<()> {
+    let mut storage = STORAGE.write();
+    let before = storage.known_hosts.len();
+    storage.known_hosts.retain(|_, entry| {
+        entry.pattern
+            || entry.port != port
+            || entry.key_type != key_type
+            || !entry.host.eq_ignore_ascii_case(host)
+    });
+    if storage.known_hosts.len() == before {
+        anyhow::bail!("No key stored for this host and key type");
+    }
+    storage.save_known_hosts()
+}
+
+/// Import entries from an OpenSSH known_hosts file. Lines that can't be read
+/// are skipped and reported back rather than failing the whole import.
+pub fn import_known_hosts(content: &str) -> Result<KnownHostsImport> {
+    let now = chrono_timestamp();
+
+    let mut entries = Vec::new();
+    let mut skipped = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_known_hosts_line(line, now) {
+            Ok(parsed) => entries.extend(parsed),
+            Err(e) => skipped.push(SkippedLine {
+                line: index + 1,
+                error: format!("{:#}", e),
+            }),
+        }
+    }
+
+    let imported = entries.len();
+    let mut storage = STORAGE.write();
+    for entry in entries {
+        let id = known_host_entry_id(&entry);
+        storage.known_hosts.insert(id, entry);
+    }
+    storage.save_known_hosts()?;
+
+    Ok(KnownHostsImport { imported, skipped })
+}
+
+/// Parses one known_hosts line into an entry per host it names
+fn parse_known_hosts_line(line: &str, added_at: i64) -> Result<Vec<KnownHost>> {
+    let mut fields = line.split_whitespace();
+    let mut hosts = fields.next().unwrap_or_default();
+    let marker = match hosts {
+        "@cert-authority" => Some(HostKeyMarker::CertAuthority),
+        "@revoked" => Some(HostKeyMarker::Revoked),
+        other if other.starts_with('@') => anyhow::bail!("Unknown marker {}", other),
+        _ => None,
+    };
+    if marker.is_some() {
+        hosts = fields.next().unwrap_or_default();
+    }
+
+    let (Some(key_type), Some(public_key)) = (fields.next(), fields.next()) else {
+        anyhow::bail!("Expected host, key type and key");
+    };
+    let fingerprint = host_key_fingerprint(public_key).context("Invalid key")?;
+    let comment = fields.collect::<Vec<_>>().join(" ");
+
+    let entry = |host: String, port: u16, pattern: bool| KnownHost {
+        host,
+        port,
+        key_type: key_type.to_string(),
+        fingerprint: fingerprint.clone(),
+        public_key: public_key.to_string(),
+        marker,
+        pattern,
+        comment: Some(comment.clone()).filter(|c| !c.is_empty()),
+        added_at,
+    };
+
+    // Plain names become regular entries, hashed names and wildcard
+    // patterns are kept verbatim. Negations only make sense next to the
+    // patterns they restrict, so they stay together with them.
+    let mut entries = Vec::new();
+    let mut patterns = Vec::new();
+    for name in hosts.split(',') {
+        if name.starts_with("|1|") {
+            entries.push(entry(name.to_string(), 0, true));
+        } else if name.contains(['*', '?', '!']) {
+            patterns.push(name);
+        } else if let Some((host, port)) = parse_known_hosts_name(name) {
+            entries.push(entry(host, port, false));
+        } else {
+            anyhow::bail!("Invalid host {}", name);
+        }
+    }
+    if !patterns.is_empty() {
+        entries.push(entry(patterns.join(","), 0, true));
+    }
+    Ok(entries)
+}
+
+/// Parses `host` or `[host]:port`
+fn parse_known_hosts_name(name: &str) -> Option<(String, u16)> {
+    match name.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest.split_once("]:")?;
+            Some((host.to_lowercase(), port.parse().ok()?))
+        }
+        None => Some((name.to_lowercase(), 22)),
+    }
+}
+
+/// Export all entries in OpenSSH known_hosts format. With `hash_hosts`,
+/// plain host names are hashed like `ssh-keygen -H` does.
+pub fn export_known_hosts(hash_hosts: bool) -> String {
+    let storage = STORAGE.read();
+    let mut entries: Vec<&KnownHost> = storage.known_hosts.values().collect();
+    entries.sort_by(|a, b| (&a.host, a.port, &a.key_type).cmp(&(&b.host, b.port, &b.key_type)));
+
+    let mut output = String::new();
+    for entry in entries {
+        match entry.marker {
+            Some(HostKeyMarker::CertAuthority) => output.push_str("@cert-authority "),
+            Some(HostKeyMarker::Revoked) => output.push_str("@revoked "),
+            None => {}
+        }
+
+        let name = if entry.pattern {
+            entry.host.clone()
+        } else if hash_hosts {
+            hash_host_name(&known_hosts_name(&entry.host, entry.port))
+        } else {
+            known_hosts_name(&entry.host, entry.port)
+        };
+        output.push_str(&format!("{} {} {}", name, entry.key_type, entry.public_key));
+
+        if let Some(comment) = &entry.comment {
+            output.push(' ');
+            output.push_str(comment);
+        }
+        output.push('\n');
+    }
+
+    output
+}
+
 /// Export all servers with password-based encryption
 pub fn export_servers(password: &str) -> Result<String> {
     use sha2::{Digest, Sha256};
@@ -390,3 +1012,94 @@ pub fn import_servers(encrypted_data: &str, password: &str) -> Result<usize> {
     storage.save()?;
     Ok(count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    fn parse(line: &str) -> Vec<KnownHost> {
+        parse_known_hosts_line(line, 0).unwrap()
+    }
+
+    #[test]
+    fn hashed_entry_matches_its_host_only() {
+        // HMAC-SHA1 of "[example.com]:2222", as written by `ssh-keygen -H`
+        let line = format!(
+            "|1|AQIDBAUGBwgJCgsMDQ4PEBESExQ=|uVLj+YL3GsbtNOcCoC7AMM/WVh0= ssh-ed25519 {}",
+            KEY
+        );
+        let entries = parse(&line);
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].pattern);
+        assert!(host_key_matches(&entries[0], "example.com", 2222));
+        assert!(host_key_matches(&entries[0], "Example.COM", 2222));
+        assert!(!host_key_matches(&entries[0], "example.com", 22));
+        assert!(!host_key_matches(&entries[0], "example.org", 2222));
+    }
+
+    #[test]
+    fn hashed_names_round_trip() {
+        let hashed = hash_host_name("example.com");
+        let entries = parse(&format!("{} ssh-ed25519 {}", hashed, KEY));
+        assert!(host_key_matches(&entries[0], "example.com", 22));
+        assert!(!host_key_matches(&entries[0], "example.com", 2222));
+    }
+
+    #[test]
+    fn negated_pattern_excludes_host() {
+        let entries = parse(&format!(
+            "*.example.com,!bad.example.com,plain.org ssh-ed25519 {}",
+            KEY
+        ));
+        // The plain name stands alone, the patterns stay together
+        assert_eq!(entries.len(), 2);
+        let pattern = entries.iter().find(|e| e.pattern).unwrap();
+        assert_eq!(pattern.host, "*.example.com,!bad.example.com");
+        assert!(host_key_matches(pattern, "good.example.com", 22));
+        assert!(!host_key_matches(pattern, "bad.example.com", 22));
+        assert!(!host_key_matches(pattern, "example.org", 22));
+        // Patterns without a port only cover port 22
+        assert!(!host_key_matches(pattern, "good.example.com", 2222));
+    }
+
+    #[test]
+    fn bracketed_ipv6_with_port() {
+        let entries = parse(&format!("[::1]:2222 ssh-ed25519 {} local", KEY));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].host, "::1");
+        assert_eq!(entries[0].port, 2222);
+        assert!(!entries[0].pattern);
+        assert_eq!(entries[0].comment.as_deref(), Some("local"));
+        assert!(host_key_matches(&entries[0], "::1", 2222));
+        assert!(!host_key_matches(&entries[0], "::1", 22));
+        assert_eq!(known_hosts_name("::1", 2222), "[::1]:2222");
+    }
+
+    #[test]
+    fn revoked_line() {
+        let entries = parse(&format!("@revoked * ssh-ed25519 {}", KEY));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].marker, Some(HostKeyMarker::Revoked));
+        assert!(host_key_matches(&entries[0], "anything.example.com", 22));
+        assert!(entries[0].fingerprint.starts_with("SHA256:"));
+    }
+
+    #[test]
+    fn unreadable_lines_are_errors() {
+        assert!(parse_known_hosts_line(&format!("@unknown host ssh-ed25519 {}", KEY), 0).is_err());
+        assert!(parse_known_hosts_line("host ssh-ed25519", 0).is_err());
+        assert!(parse_known_hosts_line("host ssh-ed25519 not-base64!", 0).is_err());
+        assert!(parse_known_hosts_line(&format!("[host]:port ssh-ed25519 {}", KEY), 0).is_err());
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_matches("*.example.com", "a.b.example.com"));
+        assert!(wildcard_matches("host?", "host1"));
+        assert!(!wildcard_matches("host?", "host12"));
+        assert!(wildcard_matches("*", ""));
+        assert!(!wildcard_matches("*.example.com", "example.com"));
+    }
+}
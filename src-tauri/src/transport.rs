@@ -0,0 +1,776 @@
+use crate::models::{
+    AgentForwardUse, AuthMethod, AuthPrompt, AuthPromptField, CertificateInfo, HostKeyDecision,
+    HostKeyPrompt, KnownHost, ProxyConfig, ProxyType, ServerConfig, StoredHostKey,
+};
+use crate::storage::{self, HostKeyStatus};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use russh::keys::*;
+use russh::*;
+use ssh_key::Certificate;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_socks::tcp::Socks5Stream;
+
+// Host key prompts waiting for the user's decision
+static HOST_KEY_PROMPTS: Lazy<Mutex<HashMap<String, oneshot::Sender<HostKeyDecision>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Keyboard-interactive prompts waiting for the user's answers, None cancels
+type AuthAnswers = Option<Vec<String>>;
+static AUTH_PROMPTS: Lazy<Mutex<HashMap<String, oneshot::Sender<AuthAnswers>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// How long a connection waits for the user to answer a prompt
+const PROMPT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// Questions raised while connecting that need an answer from the user, and
+/// notices about the connection they should see
+#[derive(Debug, Clone)]
+pub enum ConnectPrompt {
+    HostKey(HostKeyPrompt),
+    Auth(AuthPrompt),
+    /// The server had our forwarded agent sign something
+    AgentUsed(AgentForwardUse),
+}
+
+/// Channel used to ask the user about host keys and login prompts
+pub type Prompter = mpsc::UnboundedSender<ConnectPrompt>;
+
+/// An authenticated connection to a saved server. Holds the jump hosts it was
+/// tunnelled through, so dropping it tears down the whole chain.
+pub struct Connection<H: client::Handler> {
+    pub handle: client::Handle<H>,
+    _jump_hosts: Vec<client::Handle<JumpHostHandler>>,
+}
+
+/// Connects and authenticates to `server` through its proxy or jump hosts.
+/// Terminal and SFTP sessions both go through here; `handler` wraps the host
+/// key check of the final hop into the caller's own handler.
+pub async fn connect<H, F>(
+    server: &ServerConfig,
+    prompter: Option<Prompter>,
+    handler: F,
+) -> Result<Connection<H>>
+where
+    H: client::Handler<Error = anyhow::Error> + 'static,
+    F: FnOnce(HostKeyCheck) -> H,
+{
+    let config = client_config(server);
+
+    // Check if we need to go through jump hosts
+    let jump_chain = resolve_jump_chain(server)?;
+    let (stream, jump_hosts): (Box<dyn AsyncReadWrite>, _) = if jump_chain.is_empty() {
+        (Box::new(create_connection(server).await?), Vec::new())
+    } else {
+        let (stream, handles) =
+            connect_via_jump_chain(&jump_chain, server, prompter.as_ref(), config.clone()).await?;
+        (Box::new(stream), handles)
+    };
+
+    let host_key = HostKeyCheck::new(&server.host, server.port, prompter.clone());
+    let mut handle = client::connect_stream(config, stream, handler(host_key))
+        .await
+        .map_err(|e| handshake_error(e, "Failed to establish SSH connection"))?;
+
+    authenticate(&mut handle, server, prompter.as_ref()).await?;
+
+    Ok(Connection {
+        handle,
+        _jump_hosts: jump_hosts,
+    })
+}
+
+fn client_config(server: &ServerConfig) -> Arc<client::Config> {
+    let keepalive_interval = (server.keepalive_interval > 0)
+        .then(|| std::time::Duration::from_secs(server.keepalive_interval.into()));
+    let config = client::Config {
+        inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
+        keepalive_interval,
+        keepalive_max: server.keepalive_max as usize,
+        ..Default::default()
+    };
+    Arc::new(config)
+}
+
+/// Connects through every hop of the chain, each one tunnelled through the
+/// previous hop's direct-tcpip channel, and opens the tunnel to the target.
+async fn connect_via_jump_chain(
+    chain: &[ServerConfig],
+    target_server: &ServerConfig,
+    prompter: Option<&Prompter>,
+    config: Arc<client::Config>,
+) -> Result<(
+    ChannelStream<client::Msg>,
+    Vec<client::Handle<JumpHostHandler>>,
+)> {
+    let mut handles: Vec<client::Handle<JumpHostHandler>> = Vec::with_capacity(chain.len());
+    for (hop, jump_server) in chain.iter().enumerate() {
+        // The first hop is reached directly, every later one through the previous hop
+        let jump_stream: Box<dyn AsyncReadWrite> = match handles.last() {
+            None => Box::new(
+                create_connection(jump_server)
+                    .await
+                    .map_err(|e| hop_error(e, chain, hop))?,
+            ),
+            Some(previous) => Box::new(
+                open_tunnel(previous, jump_server)
+                    .await
+                    .map_err(|e| hop_error(e, chain, hop - 1))?,
+            ),
+        };
+
+        let handler = JumpHostHandler {
+            host_key: HostKeyCheck::new(&jump_server.host, jump_server.port, prompter.cloned()),
+        };
+        let mut jump_handle = client::connect_stream(config.clone(), jump_stream, handler)
+            .await
+            .map_err(|e| hop_error(handshake_error(e, "Failed to connect"), chain, hop))?;
+
+        authenticate(&mut jump_handle, jump_server, prompter)
+            .await
+            .map_err(|e| hop_error(e, chain, hop))?;
+
+        handles.push(jump_handle);
+    }
+
+    // Open direct-tcpip channel to target server from the last hop
+    let last = chain.len() - 1;
+    let channel = open_tunnel(&handles[last], target_server)
+        .await
+        .map_err(|e| hop_error(e, chain, last))?;
+
+    Ok((channel, handles))
+}
+
+async fn create_connection(server: &ServerConfig) -> Result<TcpStream> {
+    let target_addr = format!("{}:{}", server.host, server.port);
+
+    match &server.proxy {
+        Some(proxy) => connect_via_proxy(proxy, &target_addr).await,
+        None => TcpStream::connect(&target_addr)
+            .await
+            .context("Failed to connect to server"),
+    }
+}
+
+async fn connect_via_proxy(proxy: &ProxyConfig, target: &str) -> Result<TcpStream> {
+    let proxy_addr = format!("{}:{}", proxy.host, proxy.port);
+
+    match proxy.proxy_type {
+        ProxyType::Socks5 => {
+            let stream = if let (Some(user), Some(pass)) = (&proxy.username, &proxy.password) {
+                Socks5Stream::connect_with_password(proxy_addr.as_str(), target, user, pass)
+                    .await
+                    .context("Failed to connect via SOCKS5 proxy")?
+            } else {
+                Socks5Stream::connect(proxy_addr.as_str(), target)
+                    .await
+                    .context("Failed to connect via SOCKS5 proxy")?
+            };
+            Ok(stream.into_inner())
+        }
+        ProxyType::Http => {
+            // HTTP CONNECT proxy
+            let mut stream = TcpStream::connect(&proxy_addr)
+                .await
+                .context("Failed to connect to HTTP proxy")?;
+
+            let connect_request =
+                if let (Some(user), Some(pass)) = (&proxy.username, &proxy.password) {
+                    let credentials = base64::Engine::encode(
+                        &base64::engine::general_purpose::STANDARD,
+                        format!("{}:{}", user, pass),
+                    );
+                    format!(
+                        "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Basic {}\r\n\r\n",
+                        target, target, credentials
+                    )
+                } else {
+                    format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target)
+                };
+
+            stream.write_all(connect_request.as_bytes()).await?;
+
+            let mut response = vec![0u8; 1024];
+            let n = stream.read(&mut response).await?;
+            let response_str = String::from_utf8_lossy(&response[..n]);
+
+            if !response_str.contains("200") {
+                anyhow::bail!("HTTP proxy connection failed: {}", response_str);
+            }
+
+            Ok(stream)
+        }
+    }
+}
+
+async fn authenticate<H: client::Handler>(
+    handle: &mut client::Handle<H>,
+    server: &ServerConfig,
+    prompter: Option<&Prompter>,
+) -> Result<()> {
+    match &server.auth {
+        AuthMethod::Password(password) => {
+            let auth_result = handle
+                .authenticate_password(&server.username, password)
+                .await
+                .context("Password authentication failed")?;
+
+            if !auth_result && !continue_with_keyboard_interactive(handle, server, prompter).await?
+            {
+                anyhow::bail!("Invalid username or password");
+            }
+        }
+        AuthMethod::PrivateKey {
+            key,
+            passphrase,
+            certificate,
+        } => {
+            // Treat empty passphrase as None
+            let passphrase_opt = passphrase.as_ref().filter(|p| !p.is_empty());
+            let key_pair = if let Some(passphrase) = passphrase_opt {
+                decode_secret_key(key, Some(passphrase))
+                    .context("Failed to decode private key with passphrase")?
+            } else {
+                decode_secret_key(key, None).context("Failed to decode private key")?
+            };
+
+            let auth_result =
+                authenticate_with_key(handle, &server.username, key_pair, certificate.as_deref())
+                    .await
+                    .context("Public key authentication failed")?;
+
+            if !auth_result && !continue_with_keyboard_interactive(handle, server, prompter).await?
+            {
+                anyhow::bail!("Public key authentication rejected");
+            }
+        }
+        AuthMethod::Agent { fingerprint } => {
+            let auth_result =
+                authenticate_with_agent(handle, &server.username, fingerprint.as_deref())
+                    .await
+                    .context("SSH agent authentication failed")?;
+
+            if !auth_result && !continue_with_keyboard_interactive(handle, server, prompter).await?
+            {
+                anyhow::bail!("SSH agent authentication rejected");
+            }
+        }
+        AuthMethod::KeyboardInteractive => {
+            let prompter =
+                prompter.context("Keyboard-interactive authentication needs a prompt")?;
+            if !authenticate_keyboard_interactive(handle, server, prompter).await? {
+                anyhow::bail!("Keyboard-interactive authentication rejected");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HostKeyError {
+    #[error(
+        "Host key for {host}:{port} has changed! Expected {expected_type} key {expected}, \
+         but the server presented {key_type} key {presented}. Someone could be intercepting \
+         the connection; accept the new key only if you know the server was reinstalled or rekeyed."
+    )]
+    Changed {
+        host: String,
+        port: u16,
+        expected_type: String,
+        expected: String,
+        key_type: String,
+        presented: String,
+    },
+    #[error("Host key for {host}:{port} was not accepted")]
+    Rejected { host: String, port: u16 },
+    #[error("Host key {fingerprint} presented by {host}:{port} has been revoked")]
+    Revoked {
+        host: String,
+        port: u16,
+        fingerprint: String,
+    },
+}
+
+/// Expands the jump hosts of `server` into the ordered list of hops to go
+/// through. Hops are connected with their own settings, so a hop with jump
+/// hosts of its own pulls those in first, like ProxyJump does.
+pub fn resolve_jump_chain(server: &ServerConfig) -> Result<Vec<ServerConfig>> {
+    let mut path = vec![server.clone()];
+    let mut chain = Vec::new();
+    expand_jump_hosts(&mut path, &mut chain)?;
+    Ok(chain)
+}
+
+fn expand_jump_hosts(path: &mut Vec<ServerConfig>, chain: &mut Vec<ServerConfig>) -> Result<()> {
+    let server = path.last().cloned().context("Empty jump chain")?;
+
+    for jump_host_id in &server.jump_hosts {
+        let jump_server = storage::get_server(jump_host_id).with_context(|| {
+            format!(
+                "Jump host {} used by '{}' not found",
+                jump_host_id, server.name
+            )
+        })?;
+
+        if path.iter().any(|s| s.id == jump_server.id) {
+            let names: Vec<&str> = path.iter().map(|s| s.name.as_str()).collect();
+            anyhow::bail!(
+                "Jump host loop: {} -> {}",
+                names.join(" -> "),
+                jump_server.name
+            );
+        }
+        if chain.iter().any(|s| s.id == jump_server.id) {
+            anyhow::bail!(
+                "Jump host '{}' appears more than once in the chain",
+                jump_server.name
+            );
+        }
+
+        path.push(jump_server);
+        expand_jump_hosts(path, chain)?;
+        if let Some(jump_server) = path.pop() {
+            chain.push(jump_server);
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens a direct-tcpip channel from an established hop to `target`
+async fn open_tunnel<H: client::Handler>(
+    handle: &client::Handle<H>,
+    target: &ServerConfig,
+) -> Result<ChannelStream<client::Msg>> {
+    let channel = handle
+        .channel_open_direct_tcpip(&target.host, target.port as u32, "127.0.0.1", 0)
+        .await
+        .with_context(|| format!("Failed to open tunnel to {}:{}", target.host, target.port))?;
+    Ok(channel.into_stream())
+}
+
+/// Names the hop an error happened on. Host key errors already name the
+/// host and stay on top, so they can still be told apart.
+fn hop_error(err: anyhow::Error, chain: &[ServerConfig], hop: usize) -> anyhow::Error {
+    if err.is::<HostKeyError>() {
+        return err;
+    }
+    let server = &chain[hop];
+    err.context(format!(
+        "Jump host {}/{} '{}' ({}:{})",
+        hop + 1,
+        chain.len(),
+        server.name,
+        server.host,
+        server.port
+    ))
+}
+
+/// Keeps host key failures at the top of the error chain so they are not
+/// hidden behind the generic handshake error.
+fn handshake_error(err: anyhow::Error, context: &'static str) -> anyhow::Error {
+    if err.is::<HostKeyError>() {
+        err
+    } else {
+        err.context(context)
+    }
+}
+
+/// Verifies the key presented by one host against the known hosts store.
+///
+/// With a prompter, unknown and changed keys are forwarded to the user and the
+/// handshake waits for their decision. Without one, unknown hosts are trusted
+/// on first use and changed keys are rejected.
+pub(crate) struct HostKeyCheck {
+    host: String,
+    port: u16,
+    prompter: Option<Prompter>,
+}
+
+impl HostKeyCheck {
+    pub(crate) fn new(host: &str, port: u16, prompter: Option<Prompter>) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            prompter,
+        }
+    }
+
+    pub(crate) async fn verify(&self, server_key: &key::PublicKey) -> Result<bool> {
+        let key_type = match server_key {
+            // RSA keys report the negotiated signature hash as their name
+            key::PublicKey::RSA { .. } => "ssh-rsa",
+            _ => server_key.name(),
+        };
+        let public_key = server_key.public_key_base64();
+
+        let presented = KnownHost {
+            host: self.host.clone(),
+            port: self.port,
+            key_type: key_type.to_string(),
+            fingerprint: format!("SHA256:{}", server_key.fingerprint()),
+            public_key: public_key.clone(),
+            marker: None,
+            pattern: false,
+            comment: None,
+            added_at: 0,
+        };
+
+        let known = match storage::check_host_key(&self.host, self.port, key_type, &public_key) {
+            HostKeyStatus::Trusted => return Ok(true),
+            HostKeyStatus::Unknown => Vec::new(),
+            HostKeyStatus::Changed(known) => known,
+            // Revoked keys are never accepted, not even on request
+            HostKeyStatus::Revoked => {
+                return Err(HostKeyError::Revoked {
+                    host: self.host.clone(),
+                    port: self.port,
+                    fingerprint: presented.fingerprint,
+                }
+                .into())
+            }
+        };
+
+        let Some(prompter) = &self.prompter else {
+            return match known.first() {
+                None => {
+                    storage::trust_host_key(presented)?;
+                    Ok(true)
+                }
+                Some(expected) => Err(HostKeyError::Changed {
+                    host: self.host.clone(),
+                    port: self.port,
+                    expected_type: expected.key_type.clone(),
+                    expected: expected.fingerprint.clone(),
+                    key_type: presented.key_type,
+                    presented: presented.fingerprint,
+                }
+                .into()),
+            };
+        };
+
+        let prompt = HostKeyPrompt {
+            prompt_id: uuid::Uuid::new_v4().to_string(),
+            host: self.host.clone(),
+            port: self.port,
+            key_type: presented.key_type.clone(),
+            fingerprint: presented.fingerprint.clone(),
+            previous_keys: known
+                .into_iter()
+                .map(|k| StoredHostKey {
+                    key_type: k.key_type,
+                    fingerprint: k.fingerprint,
+                })
+                .collect(),
+        };
+
+        match ask_host_key_decision(prompter, prompt).await {
+            HostKeyDecision::AcceptOnce => Ok(true),
+            HostKeyDecision::AcceptAndSave => {
+                storage::trust_host_key(presented)?;
+                Ok(true)
+            }
+            HostKeyDecision::Reject => Err(HostKeyError::Rejected {
+                host: self.host.clone(),
+                port: self.port,
+            }
+            .into()),
+        }
+    }
+}
+
+/// Sends a prompt to the user and waits for the answer. No answer within the
+/// timeout counts as a rejection.
+async fn ask_host_key_decision(prompter: &Prompter, prompt: HostKeyPrompt) -> HostKeyDecision {
+    let prompt_id = prompt.prompt_id.clone();
+    let (decision_tx, decision_rx) = oneshot::channel();
+    HOST_KEY_PROMPTS
+        .lock()
+        .await
+        .insert(prompt_id.clone(), decision_tx);
+
+    if prompter.send(ConnectPrompt::HostKey(prompt)).is_err() {
+        HOST_KEY_PROMPTS.lock().await.remove(&prompt_id);
+        return HostKeyDecision::Reject;
+    }
+
+    match tokio::time::timeout(PROMPT_TIMEOUT, decision_rx).await {
+        Ok(Ok(decision)) => decision,
+        _ => {
+            HOST_KEY_PROMPTS.lock().await.remove(&prompt_id);
+            HostKeyDecision::Reject
+        }
+    }
+}
+
+/// Delivers the user's answer to a pending host key prompt
+pub async fn answer_host_key_prompt(prompt_id: &str, decision: HostKeyDecision) -> Result<()> {
+    let decision_tx = HOST_KEY_PROMPTS
+        .lock()
+        .await
+        .remove(prompt_id)
+        .context("Host key prompt not found or already answered")?;
+    decision_tx
+        .send(decision)
+        .map_err(|_| anyhow::anyhow!("Connection is no longer waiting for this host key"))
+}
+
+/// Runs keyboard-interactive authentication, forwarding every round of
+/// prompts from the server to the user.
+async fn authenticate_keyboard_interactive<H: client::Handler>(
+    handle: &mut client::Handle<H>,
+    server: &ServerConfig,
+    prompter: &Prompter,
+) -> Result<bool> {
+    let mut response = handle
+        .authenticate_keyboard_interactive_start(&server.username, None)
+        .await
+        .context("Keyboard-interactive authentication failed")?;
+
+    loop {
+        let (name, instructions, prompts) = match response {
+            client::KeyboardInteractiveAuthResponse::Success => return Ok(true),
+            client::KeyboardInteractiveAuthResponse::Failure => return Ok(false),
+            client::KeyboardInteractiveAuthResponse::InfoRequest {
+                name,
+                instructions,
+                prompts,
+            } => (name, instructions, prompts),
+        };
+
+        // Servers may send rounds without prompts, those need no input
+        let answers = if prompts.is_empty() {
+            Vec::new()
+        } else {
+            let prompt = AuthPrompt {
+                prompt_id: uuid::Uuid::new_v4().to_string(),
+                host: server.host.clone(),
+                port: server.port,
+                username: server.username.clone(),
+                name,
+                instructions,
+                prompts: prompts
+                    .into_iter()
+                    .map(|p| AuthPromptField {
+                        prompt: p.prompt,
+                        echo: p.echo,
+                    })
+                    .collect(),
+            };
+            ask_auth_answers(prompter, prompt).await?
+        };
+
+        response = handle
+            .authenticate_keyboard_interactive_respond(answers)
+            .await
+            .context("Keyboard-interactive authentication failed")?;
+    }
+}
+
+/// Continues with keyboard-interactive after the configured method was rejected.
+///
+/// Servers requiring several methods, e.g. publickey followed by an OTP, answer
+/// the first one with a partial success, which russh reports as a failure. If
+/// the server has nothing to ask, the original rejection stands.
+async fn continue_with_keyboard_interactive<H: client::Handler>(
+    handle: &mut client::Handle<H>,
+    server: &ServerConfig,
+    prompter: Option<&Prompter>,
+) -> Result<bool> {
+    match prompter {
+        Some(prompter) if !handle.is_closed() => {
+            authenticate_keyboard_interactive(handle, server, prompter).await
+        }
+        _ => Ok(false),
+    }
+}
+
+async fn ask_auth_answers(prompter: &Prompter, prompt: AuthPrompt) -> Result<Vec<String>> {
+    let prompt_id = prompt.prompt_id.clone();
+    let expected = prompt.prompts.len();
+    let (answers_tx, answers_rx) = oneshot::channel();
+    AUTH_PROMPTS
+        .lock()
+        .await
+        .insert(prompt_id.clone(), answers_tx);
+
+    if prompter.send(ConnectPrompt::Auth(prompt)).is_err() {
+        AUTH_PROMPTS.lock().await.remove(&prompt_id);
+        anyhow::bail!("Authentication prompt could not be shown");
+    }
+
+    let answers = match tokio::time::timeout(PROMPT_TIMEOUT, answers_rx).await {
+        Ok(Ok(answers)) => answers,
+        _ => {
+            AUTH_PROMPTS.lock().await.remove(&prompt_id);
+            anyhow::bail!("Timed out waiting for authentication prompt answers");
+        }
+    };
+
+    let answers = answers.context("Authentication cancelled")?;
+    if answers.len() != expected {
+        anyhow::bail!("Expected {} answers, got {}", expected, answers.len());
+    }
+    Ok(answers)
+}
+
+/// Delivers the user's answers to a pending keyboard-interactive prompt,
+/// None cancels the login
+pub async fn answer_auth_prompt(prompt_id: &str, answers: AuthAnswers) -> Result<()> {
+    let answers_tx = AUTH_PROMPTS
+        .lock()
+        .await
+        .remove(prompt_id)
+        .context("Authentication prompt not found or already answered")?;
+    answers_tx
+        .send(answers)
+        .map_err(|_| anyhow::anyhow!("Connection is no longer waiting for these answers"))
+}
+
+/// Authenticates with a private key, presenting its OpenSSH certificate
+/// (`-cert-v01@openssh.com` algorithms) when one is configured.
+async fn authenticate_with_key<H: client::Handler>(
+    handle: &mut client::Handle<H>,
+    username: &str,
+    key_pair: key::KeyPair,
+    certificate: Option<&str>,
+) -> Result<bool> {
+    let Some(certificate) = certificate.filter(|c| !c.trim().is_empty()) else {
+        return Ok(handle
+            .authenticate_publickey(username, Arc::new(key_pair))
+            .await?);
+    };
+
+    let certificate = parse_certificate(certificate)?;
+    let certified_key = ssh_key::PublicKey::from(certificate.public_key().clone())
+        .to_openssh()
+        .context("Failed to encode certificate key")?;
+    if certified_key.split_whitespace().nth(1) != Some(key_pair.public_key_base64().as_str()) {
+        anyhow::bail!("Certificate was not issued for this private key");
+    }
+
+    Ok(handle
+        .authenticate_openssh_cert(username, Arc::new(key_pair), certificate)
+        .await?)
+}
+
+fn parse_certificate(certificate: &str) -> Result<Certificate> {
+    Certificate::from_openssh(certificate.trim()).context("Failed to parse OpenSSH certificate")
+}
+
+/// Reads the key ID, principals and validity window of a user certificate
+pub fn certificate_info(certificate: &str) -> Result<CertificateInfo> {
+    let certificate = parse_certificate(certificate)?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    Ok(CertificateInfo {
+        key_id: certificate.key_id().to_string(),
+        principals: certificate.valid_principals().to_vec(),
+        valid_after: Some(certificate.valid_after()).filter(|t| *t != 0),
+        valid_before: Some(certificate.valid_before()).filter(|t| *t != u64::MAX),
+        expired: certificate.valid_before() <= now,
+    })
+}
+
+type AgentSocket = Box<dyn agent::client::AgentStream + Send + Unpin + 'static>;
+type DynAgentClient = agent::client::AgentClient<AgentSocket>;
+
+/// Opens the local ssh-agent socket, used both to log in and to serve
+/// forwarded agent channels
+#[cfg(unix)]
+pub(crate) async fn connect_agent_socket() -> Result<AgentSocket> {
+    let path = std::env::var("SSH_AUTH_SOCK")
+        .context("Failed to connect to SSH agent, is SSH_AUTH_SOCK set?")?;
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .context("Failed to connect to SSH agent")?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(windows)]
+pub(crate) async fn connect_agent_socket() -> Result<AgentSocket> {
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new()
+        .open(r"\\.\pipe\openssh-ssh-agent")
+        .context("Failed to connect to the OpenSSH agent service")?;
+    Ok(Box::new(stream))
+}
+
+async fn connect_agent() -> Result<DynAgentClient> {
+    Ok(agent::client::AgentClient::connect(
+        connect_agent_socket().await?,
+    ))
+}
+
+/// Offers each identity held by the local ssh-agent in turn, or only the one
+/// matching `fingerprint` when set. Returns whether the server accepted one.
+async fn authenticate_with_agent<H: client::Handler>(
+    handle: &mut client::Handle<H>,
+    username: &str,
+    fingerprint: Option<&str>,
+) -> Result<bool> {
+    let mut agent = connect_agent().await?;
+    let mut identities = agent
+        .request_identities()
+        .await
+        .context("Failed to list SSH agent identities")?;
+
+    let wanted = fingerprint
+        .map(|f| f.trim().trim_start_matches("SHA256:"))
+        .filter(|f| !f.is_empty());
+    if let Some(wanted) = wanted {
+        identities.retain(|key| key.fingerprint() == wanted);
+        if identities.is_empty() {
+            anyhow::bail!(
+                "SSH agent has no identity with fingerprint SHA256:{}",
+                wanted
+            );
+        }
+    } else if identities.is_empty() {
+        anyhow::bail!("SSH agent has no identities");
+    }
+
+    for key in identities {
+        // Boxed so the future stays provably Send when reconnecting from a
+        // spawned task; the generic signer trips up the compiler otherwise
+        let authenticate: std::pin::Pin<Box<dyn std::future::Future<Output = _> + Send + '_>> =
+            Box::pin(handle.authenticate_future(username, key, agent));
+        let (returned_agent, auth_result) = authenticate.await;
+        agent = returned_agent;
+
+        if auth_result.context("SSH agent failed to sign the authentication request")? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Trait for async read/write streams
+trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncReadWrite for T {}
+
+struct JumpHostHandler {
+    host_key: HostKeyCheck,
+}
+
+#[async_trait]
+impl client::Handler for JumpHostHandler {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        self.host_key.verify(server_public_key).await
+    }
+}
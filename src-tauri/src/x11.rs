@@ -0,0 +1,341 @@
+use anyhow::{bail, Context, Result};
+use rand::Rng;
+use russh::{client, Channel};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const AUTH_PROTOCOL: &str = "MIT-MAGIC-COOKIE-1";
+const COOKIE_LEN: usize = 16;
+
+// Xauthority address families
+const FAMILY_LOCAL: u16 = 256;
+const FAMILY_WILD: u16 = 65535;
+
+/// Local displays that forwarded X11 channels may reach, keyed by the fake
+/// cookie handed to the server for each shell
+pub(crate) type X11Displays = Arc<parking_lot::Mutex<HashMap<Vec<u8>, Arc<LocalDisplay>>>>;
+
+/// Where an X11 client on the server ends up, and the real cookie to use
+pub(crate) struct LocalDisplay {
+    target: DisplayTarget,
+    cookie: Option<Vec<u8>>,
+}
+
+/// The fake cookie of one shell. X11 channels presenting it are accepted
+/// until it is dropped.
+pub(crate) struct X11Cookie {
+    displays: X11Displays,
+    fake_cookie: Vec<u8>,
+}
+
+impl Drop for X11Cookie {
+    fn drop(&mut self) {
+        self.displays.lock().remove(&self.fake_cookie);
+    }
+}
+
+enum DisplayTarget {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(String, u16),
+}
+
+/// Asks the server to forward X11 for the shell on `channel`. The server only
+/// ever sees a random cookie; the real one is swapped in as each X11
+/// connection comes back.
+pub(crate) async fn request(
+    channel: &Channel<client::Msg>,
+    displays: &X11Displays,
+) -> Result<X11Cookie> {
+    let (target, number, screen) = local_display()?;
+    let display = LocalDisplay {
+        target,
+        cookie: real_cookie(&number),
+    };
+
+    let fake_cookie: Vec<u8> = rand::thread_rng().gen::<[u8; COOKIE_LEN]>().to_vec();
+    let fake_hex: String = fake_cookie.iter().map(|b| format!("{:02x}", b)).collect();
+    displays
+        .lock()
+        .insert(fake_cookie.clone(), Arc::new(display));
+    let cookie = X11Cookie {
+        displays: displays.clone(),
+        fake_cookie,
+    };
+
+    channel
+        .request_x11(false, false, AUTH_PROTOCOL, fake_hex, screen)
+        .await
+        .context("Failed to request X11 forwarding")?;
+    Ok(cookie)
+}
+
+/// Splices an `x11` channel opened by the server to the local display
+pub(crate) fn accept(channel: Channel<client::Msg>, displays: X11Displays) {
+    tokio::spawn(async move {
+        let mut remote = channel.into_stream();
+        let Ok((setup, display)) = read_setup(&mut remote, &displays).await else {
+            // Wrong cookie or garbage, hang up on the client
+            remote.shutdown().await.ok();
+            return;
+        };
+
+        let result = match &display.target {
+            #[cfg(unix)]
+            DisplayTarget::Unix(path) => match tokio::net::UnixStream::connect(path).await {
+                Ok(local) => splice(remote, local, &setup).await,
+                Err(e) => Err(e.into()),
+            },
+            DisplayTarget::Tcp(host, port) => {
+                match TcpStream::connect((host.as_str(), *port)).await {
+                    Ok(local) => splice(remote, local, &setup).await,
+                    Err(e) => Err(e.into()),
+                }
+            }
+        };
+        result.ok();
+    });
+}
+
+/// Reads the client's connection setup, checks the fake cookie and rewrites
+/// it with the real one (or no auth when the display has no cookie)
+async fn read_setup<S>(
+    stream: &mut S,
+    displays: &X11Displays,
+) -> Result<(Vec<u8>, Arc<LocalDisplay>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 12];
+    stream.read_exact(&mut header).await?;
+    let read_u16 = |offset: usize| {
+        let bytes = [header[offset], header[offset + 1]];
+        match header[0] {
+            b'B' => Ok(u16::from_be_bytes(bytes)),
+            b'l' => Ok(u16::from_le_bytes(bytes)),
+            _ => bail!("Invalid X11 byte order"),
+        }
+    };
+    let name_len = read_u16(6)? as usize;
+    let data_len = read_u16(8)? as usize;
+
+    let mut name = vec![0u8; padded(name_len)];
+    stream.read_exact(&mut name).await?;
+    let mut data = vec![0u8; padded(data_len)];
+    stream.read_exact(&mut data).await?;
+    name.truncate(name_len);
+    data.truncate(data_len);
+
+    if name != AUTH_PROTOCOL.as_bytes() {
+        bail!("Unexpected X11 authentication protocol");
+    }
+    let display = displays
+        .lock()
+        .get(&data)
+        .cloned()
+        .context("X11 connection with an unknown cookie")?;
+
+    let (name, data) = match &display.cookie {
+        Some(cookie) => (AUTH_PROTOCOL.as_bytes(), cookie.as_slice()),
+        None => (&[][..], &[][..]),
+    };
+    let write_u16 = |value: u16| match header[0] {
+        b'B' => value.to_be_bytes(),
+        _ => value.to_le_bytes(),
+    };
+
+    let mut setup = header.to_vec();
+    setup[6..8].copy_from_slice(&write_u16(name.len() as u16));
+    setup[8..10].copy_from_slice(&write_u16(data.len() as u16));
+    setup.extend_from_slice(name);
+    setup.resize(setup.len() + padded(name.len()) - name.len(), 0);
+    setup.extend_from_slice(data);
+    setup.resize(setup.len() + padded(data.len()) - data.len(), 0);
+
+    Ok((setup, display))
+}
+
+async fn splice<R, L>(remote: R, mut local: L, setup: &[u8]) -> Result<()>
+where
+    R: AsyncRead + AsyncWrite + Unpin,
+    L: AsyncRead + AsyncWrite + Unpin,
+{
+    local.write_all(setup).await?;
+    let mut remote = remote;
+    tokio::io::copy_bidirectional(&mut remote, &mut local).await?;
+    Ok(())
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Parses `DISPLAY` into the socket to reach, the display number and screen
+fn local_display() -> Result<(DisplayTarget, String, u32)> {
+    let display = std::env::var("DISPLAY")
+        .ok()
+        .filter(|d| !d.is_empty())
+        .unwrap_or_else(|| default_display().to_string());
+
+    let (host, rest) = display
+        .rsplit_once(':')
+        .with_context(|| format!("Invalid DISPLAY '{}'", display))?;
+    let (number, screen) = rest.split_once('.').unwrap_or((rest, "0"));
+    let display_number: u16 = number
+        .parse()
+        .with_context(|| format!("Invalid DISPLAY '{}'", display))?;
+    let screen = screen.parse().unwrap_or(0);
+
+    let target = match host {
+        // XQuartz hands out a launchd socket path
+        #[cfg(unix)]
+        path if path.starts_with('/') => DisplayTarget::Unix(PathBuf::from(&display)),
+        #[cfg(unix)]
+        "" | "unix" => {
+            DisplayTarget::Unix(PathBuf::from(format!("/tmp/.X11-unix/X{}", display_number)))
+        }
+        host => {
+            let host = if host.is_empty() { "localhost" } else { host };
+            DisplayTarget::Tcp(host.to_string(), 6000 + display_number)
+        }
+    };
+
+    Ok((target, number.to_string(), screen))
+}
+
+#[cfg(unix)]
+fn default_display() -> &'static str {
+    ":0"
+}
+
+// X servers on Windows (VcXsrv, Xming) listen on TCP
+#[cfg(windows)]
+fn default_display() -> &'static str {
+    "localhost:0"
+}
+
+/// Looks up the MIT-MAGIC-COOKIE-1 for a local display number in Xauthority
+fn real_cookie(number: &str) -> Option<Vec<u8>> {
+    let path = std::env::var_os("XAUTHORITY")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".Xauthority")))?;
+    let contents = std::fs::read(path).ok()?;
+
+    let mut fallback = None;
+    let mut input = contents.as_slice();
+    while !input.is_empty() {
+        let family = read_u16_be(&mut input)?;
+        let _address = read_field(&mut input)?;
+        let entry_number = read_field(&mut input)?;
+        let name = read_field(&mut input)?;
+        let data = read_field(&mut input)?;
+
+        if entry_number != number.as_bytes() || name != AUTH_PROTOCOL.as_bytes() {
+            continue;
+        }
+        if family == FAMILY_LOCAL || family == FAMILY_WILD {
+            return Some(data.to_vec());
+        }
+        fallback.get_or_insert_with(|| data.to_vec());
+    }
+    fallback
+}
+
+fn read_u16_be(input: &mut &[u8]) -> Option<u16> {
+    let (bytes, rest) = input.split_first_chunk::<2>()?;
+    *input = rest;
+    Some(u16::from_be_bytes(*bytes))
+}
+
+fn read_field<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_u16_be(input)? as usize;
+    if input.len() < len {
+        return None;
+    }
+    let (field, rest) = input.split_at(len);
+    *input = rest;
+    Some(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAKE: [u8; COOKIE_LEN] = [0xaa; COOKIE_LEN];
+    const REAL: [u8; COOKIE_LEN] = [0x55; COOKIE_LEN];
+
+    fn displays(cookie: Option<Vec<u8>>) -> X11Displays {
+        let display = LocalDisplay {
+            target: DisplayTarget::Tcp("localhost".to_string(), 6000),
+            cookie,
+        };
+        let displays = X11Displays::default();
+        displays.lock().insert(FAKE.to_vec(), Arc::new(display));
+        displays
+    }
+
+    /// A connection setup as a client sends it, in either byte order
+    fn setup(byte_order: u8, name: &[u8], data: &[u8]) -> Vec<u8> {
+        let u16_bytes = |value: u16| match byte_order {
+            b'B' => value.to_be_bytes(),
+            _ => value.to_le_bytes(),
+        };
+        let mut setup = vec![byte_order, 0];
+        setup.extend_from_slice(&u16_bytes(11));
+        setup.extend_from_slice(&u16_bytes(0));
+        setup.extend_from_slice(&u16_bytes(name.len() as u16));
+        setup.extend_from_slice(&u16_bytes(data.len() as u16));
+        setup.extend_from_slice(&[0, 0]);
+        for field in [name, data] {
+            setup.extend_from_slice(field);
+            setup.resize(setup.len() + padded(field.len()) - field.len(), 0);
+        }
+        setup
+    }
+
+    #[tokio::test]
+    async fn swaps_in_the_real_cookie() {
+        let displays = displays(Some(REAL.to_vec()));
+        for byte_order in [b'l', b'B'] {
+            let sent = setup(byte_order, AUTH_PROTOCOL.as_bytes(), &FAKE);
+            let (rewritten, _) = read_setup(&mut sent.as_slice(), &displays).await.unwrap();
+            assert_eq!(
+                rewritten,
+                setup(byte_order, AUTH_PROTOCOL.as_bytes(), &REAL)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_auth_for_displays_without_a_cookie() {
+        let displays = displays(None);
+        let sent = setup(b'l', AUTH_PROTOCOL.as_bytes(), &FAKE);
+        let (rewritten, _) = read_setup(&mut sent.as_slice(), &displays).await.unwrap();
+        assert_eq!(rewritten, setup(b'l', b"", b""));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_cookies() {
+        let displays = displays(Some(REAL.to_vec()));
+        let sent = setup(b'l', AUTH_PROTOCOL.as_bytes(), &REAL);
+        assert!(read_setup(&mut sent.as_slice(), &displays).await.is_err());
+        let sent = setup(b'l', b"XDM-AUTHORIZATION-1", &FAKE);
+        assert!(read_setup(&mut sent.as_slice(), &displays).await.is_err());
+        let sent = setup(b'x', AUTH_PROTOCOL.as_bytes(), &FAKE);
+        assert!(read_setup(&mut sent.as_slice(), &displays).await.is_err());
+    }
+
+    #[test]
+    fn dropping_the_cookie_forgets_the_display() {
+        let displays = displays(None);
+        let cookie = X11Cookie {
+            displays: displays.clone(),
+            fake_cookie: FAKE.to_vec(),
+        };
+        drop(cookie);
+        assert!(displays.lock().is_empty());
+    }
+}
@@ -26,17 +26,90 @@ export async function importServers(encryptedData, password) {
   return await invoke("import_servers", { encryptedData, password });
 }
 
+// Known Hosts
+export async function getKnownHosts() {
+  return await invoke("get_known_hosts");
+}
+
+export async function acceptHostKey(host, port, keyType, publicKey) {
+  return await invoke("accept_host_key", { host, port, keyType, publicKey });
+}
+
+export async function revokeHostKey(host, port, keyType) {
+  return await invoke("revoke_host_key", { host, port, keyType });
+}
+
+export async function forgetHostKey(host, port, keyType) {
+  return await invoke("forget_host_key", { host, port, keyType });
+}
+
+export async function importKnownHosts(content) {
+  return await invoke("import_known_hosts", { content });
+}
+
+export async function exportKnownHosts(hashHosts) {
+  return await invoke("export_known_hosts", { hashHosts });
+}
+
+// decision: "accept_once" | "accept_and_save" | "reject"
+export async function respondHostKey(promptId, decision) {
+  return await invoke("respond_host_key", { promptId, decision });
+}
+
+export function onHostKeyPrompt(callback) {
+  return listen("host-key-prompt", (event) => {
+    callback(event.payload);
+  });
+}
+
 // SSH
+// answers: one string per prompt, or null to cancel the login
+export async function respondAuthPrompt(promptId, answers) {
+  return await invoke("respond_auth_prompt", { promptId, answers });
+}
+
+export function onAuthPrompt(callback) {
+  return listen("auth-prompt", (event) => {
+    callback(event.payload);
+  });
+}
+
+export function onAgentForwardUsed(callback) {
+  return listen("agent-forward-used", (event) => {
+    callback(event.payload);
+  });
+}
+
 export async function sshConnect(serverId, cols, rows) {
   return await invoke("ssh_connect", { serverId, cols, rows });
 }
 
-export async function sshWrite(sessionId, data) {
-  return await invoke("ssh_write", { sessionId, data: Array.from(data) });
+export async function sshOpenChannel(sessionId, cols, rows) {
+  return await invoke("ssh_open_channel", { sessionId, cols, rows });
+}
+
+export async function sshWrite(sessionId, data, channelId = null) {
+  return await invoke("ssh_write", { sessionId, channelId, data: Array.from(data) });
 }
 
-export async function sshResize(sessionId, cols, rows) {
-  return await invoke("ssh_resize", { sessionId, cols, rows });
+export async function sshResize(sessionId, cols, rows, channelId = null) {
+  return await invoke("ssh_resize", { sessionId, channelId, cols, rows });
+}
+
+export async function sshCloseChannel(sessionId, channelId) {
+  return await invoke("ssh_close_channel", { sessionId, channelId });
+}
+
+export async function sshCreateBroadcastGroup(members) {
+  return await invoke("ssh_create_broadcast_group", { members });
+}
+
+export async function sshBroadcast(groupId, data) {
+  return await invoke("ssh_broadcast", { groupId, data: Array.from(data) });
+}
+
+export async function sshRemoveBroadcastGroup(groupId) {
+  return await invoke("ssh_remove_broadcast_group", { groupId });
 }
 
 export async function sshDisconnect(sessionId) {
@@ -49,6 +122,47 @@ export function onSshData(sessionId, callback) {
   });
 }
 
+export function onSshState(sessionId, callback) {
+  return listen(`ssh-state-${sessionId}`, (event) => {
+    callback(event.payload);
+  });
+}
+
+export function onSshExit(sessionId, callback) {
+  return listen(`ssh-exit-${sessionId}`, (event) => {
+    callback(event.payload);
+  });
+}
+
+export async function sshExec(serverId, command, pty = false, timeoutSecs = null) {
+  return await invoke("ssh_exec", { serverId, command, pty, timeoutSecs });
+}
+
+export async function sshExecStream(serverId, command, onOutput, pty = false, timeoutSecs = null) {
+  const execId = crypto.randomUUID();
+  const unlisten = await listen(`ssh-exec-${execId}`, (event) => {
+    onOutput({ ...event.payload, data: new Uint8Array(event.payload.data) });
+  });
+  try {
+    return await invoke("ssh_exec_stream", { serverId, command, execId, pty, timeoutSecs });
+  } finally {
+    unlisten();
+  }
+}
+
+// target: { type: "servers", value: [ids] } | { type: "tag", value: tag }
+export async function fleetRun(target, command, onProgress, concurrency = 10, timeoutSecs = null, pty = false) {
+  const runId = crypto.randomUUID();
+  const unlisten = await listen(`fleet-progress-${runId}`, (event) => {
+    onProgress(event.payload);
+  });
+  try {
+    return await invoke("fleet_run", { target, command, runId, concurrency, pty, timeoutSecs });
+  } finally {
+    unlisten();
+  }
+}
+
 // SFTP
 export async function sftpConnect(serverId) {
   return await invoke("sftp_connect", { serverId });
@@ -66,8 +180,9 @@ export async function sftpWriteFile(sessionId, path, contents) {
   return await invoke("sftp_write_file", { sessionId, path, contents: Array.from(contents) });
 }
 
-export async function sftpDelete(sessionId, path, isDir) {
-  return await invoke("sftp_delete", { sessionId, path, isDir });
+// recursive: delete a directory along with everything in it
+export async function sftpDelete(sessionId, path, isDir, recursive = false) {
+  return await invoke("sftp_delete", { sessionId, path, isDir, recursive });
 }
 
 export async function sftpRename(sessionId, oldPath, newPath) {
@@ -86,10 +201,88 @@ export async function sftpCreateFile(sessionId, path) {
   return await invoke("sftp_create_file", { sessionId, path });
 }
 
-export async function sftpDownload(sessionId, remotePath, localPath) {
-  return await invoke("sftp_download", { sessionId, remotePath, localPath });
+// Directories are transferred with everything in them. Resolves to
+// { files, bytes, failures: [{ path, error }] }
+// transferId: picked by the caller, for progress events and cancelling
+// resume: "restart" | "append" | "verify" (append only if the source is unchanged)
+export async function sftpDownload(sessionId, remotePath, localPath, transferId, resume = "restart") {
+  return await invoke("sftp_download", { sessionId, remotePath, localPath, transferId, resume });
+}
+
+export async function sftpUpload(sessionId, localPath, remotePath, transferId, resume = "restart") {
+  return await invoke("sftp_upload", { sessionId, localPath, remotePath, transferId, resume });
+}
+
+export async function sftpCancelTransfer(transferId, removePartial = false) {
+  return await invoke("sftp_cancel_transfer", { transferId, removePartial });
 }
 
-export async function sftpUpload(sessionId, localPath, remotePath) {
-  return await invoke("sftp_upload", { sessionId, localPath, remotePath });
+export function onSftpProgress(transferId, callback) {
+  return listen(`sftp-progress-${transferId}`, (event) => {
+    callback(event.payload);
+  });
+}
+
+// Transfer queue
+// transfers: [{ server_id, direction: "download" | "upload", remote_path, local_path }]
+export async function transferQueueAdd(transfers) {
+  return await invoke("transfer_queue_add", { transfers });
+}
+
+// Resolves to { paused, transfers: [{ id, server_id, direction, remote_path,
+// local_path, state: "pending" | "running" | "done" | "failed", attempts,
+// retry_at, error, summary, created_at }] }
+export async function transferQueueList() {
+  return await invoke("transfer_queue_list");
+}
+
+export async function transferQueuePause() {
+  return await invoke("transfer_queue_pause");
+}
+
+export async function transferQueueResume() {
+  return await invoke("transfer_queue_resume");
+}
+
+export async function transferQueueRemove(id) {
+  return await invoke("transfer_queue_remove", { id });
+}
+
+export async function transferQueueRetry(id) {
+  return await invoke("transfer_queue_retry", { id });
+}
+
+export async function transferQueueClearDone() {
+  return await invoke("transfer_queue_clear_done");
+}
+
+export function onTransferQueueChanged(callback) {
+  return listen("transfer-queue-changed", (event) => {
+    callback(event.payload);
+  });
+}
+
+export function onTransferQueueProgress(callback) {
+  return listen("transfer-queue-progress", (event) => {
+    callback(event.payload);
+  });
+}
+
+// Port forwarding
+export async function startForward(serverId, forwardId) {
+  return await invoke("start_forward", { serverId, forwardId });
+}
+
+export async function stopForward(forwardId) {
+  return await invoke("stop_forward", { forwardId });
+}
+
+export async function listForwards() {
+  return await invoke("list_forwards");
+}
+
+export function onForwardConnection(callback) {
+  return listen("forward-connection", (event) => {
+    callback(event.payload);
+  });
 }